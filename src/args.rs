@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+
+use crate::state::value::Value;
 
 #[derive(Parser)]
 pub struct Project4 {
@@ -8,9 +10,75 @@ pub struct Project4 {
     #[arg(short = 'h')]
     pub hostsfile: PathBuf,
 
-    #[arg(short = 'v')]
-    pub proposal_value: Option<char>,
+    // Values to decide, one slot each, in the order given.
+    // `@path` proposes the raw contents of that file
+    #[arg(short = 'v', value_parser = Value::parse)]
+    pub proposal_values: Vec<Value>,
+
+    // Largest value, in bytes, this peer will put up for a vote
+    #[arg(short = 's', default_value_t = 64 * 1024)]
+    pub max_value_size: usize,
 
     #[arg(short = 't')]
     pub proposal_delay: Option<u64>,
+
+    // Batch proposals up to this many bytes of values instead of deciding
+    // each one in its own slot. Capped at the `-s` limit
+    #[arg(short = 'b')]
+    pub batch_size: Option<usize>,
+
+    // Milliseconds a value waits for its batch to fill before the batch goes
+    // out anyway
+    #[arg(short = 'w', default_value_t = 0)]
+    pub batch_wait: u64,
+
+    // Slots the leader of a classic stage keeps in the accept phase at once
+    #[arg(short = 'p', default_value_t = 1)]
+    pub window: usize,
+
+    // Have the leader of a classic stage take leases from its acceptors and
+    // serve `read KEY` from clients under them. Without, each read waits on
+    // a heartbeat round instead
+    #[arg(short = 'l')]
+    pub leases: bool,
+
+    // Directory acceptors keep their write-ahead log in
+    #[arg(short = 'd')]
+    pub data_dir: Option<PathBuf>,
+
+    // Protocol every peer runs. Has to be the same across the hostsfile
+    #[arg(short = 'e', value_enum, default_value_t = Engine::Paxos)]
+    pub engine: Engine,
+}
+
+impl Project4 {
+    /// Parses the command line, and exits on a `-v` value over the `-s`
+    /// limit before we ever join the cluster
+    pub fn load() -> Self {
+        let arguments = Self::parse();
+        if let Some(v) = arguments
+            .proposal_values
+            .iter()
+            .find(|v| v.size() > arguments.max_value_size)
+        {
+            Self::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "-v value is {} bytes, the -s limit is {}",
+                        v.size(),
+                        arguments.max_value_size
+                    ),
+                )
+                .exit();
+        }
+        arguments
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Engine {
+    Paxos,
+    // Acceptors are the raft servers, proposers its clients
+    Raft,
 }
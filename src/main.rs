@@ -1,9 +1,8 @@
 use std::{thread::sleep, time::Duration};
 
 use args::Project4;
-use clap::Parser;
-use setup::{hostsfile::PeerList, socketry::Nexus};
-use state::Data;
+use setup::{clients::Clients, hostsfile::PeerList, socketry::Nexus};
+use state::{batch::Batcher, Data, Settings};
 
 mod args;
 mod messaging;
@@ -12,27 +11,60 @@ mod state;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let arguments = Project4::parse();
+    let arguments = Project4::load();
 
     // hostsfile reader that can give us information about peers
     let peer_list = PeerList::load(arguments.hostsfile)?;
     // collection of the incoming and outgoing channels to peers
     let nexus = Nexus::new(&peer_list).await;
+    // values from outside clients, on their own port
+    let clients = Clients::new(&peer_list).await?;
+    // values waiting to go out, batched together if `-b` was given
+    let batcher = Batcher::new(
+        arguments
+            .batch_size
+            .map(|size| size.min(arguments.max_value_size)),
+        Duration::from_millis(arguments.batch_wait),
+    );
     // Add this sleep to allow other peers in the system to finish setting up
     sleep(Duration::from_secs(2));
 
-    let mut data = Data::new(peer_list, nexus);
+    let mut data = Data::new(
+        peer_list,
+        nexus,
+        clients,
+        batcher,
+        Settings {
+            engine: arguments.engine,
+            data_dir: arguments.data_dir,
+            window: arguments.window,
+            leases: arguments.leases,
+        },
+        arguments.max_value_size,
+    )?;
+    let mut values = arguments.proposal_values.into_iter();
+    // batched values go out with the clients' ones, so `-t` doesn't apply
+    if data.is_batching() {
+        values.by_ref().for_each(|value| data.queue(value));
+    }
     loop {
-        if let Some(value) = arguments.proposal_value {
-            if data.can_propose() {
+        if data.can_propose() {
+            if let Some(value) = values.next() {
                 if let Some(secs) = arguments.proposal_delay {
                     sleep(Duration::from_secs(secs));
                 }
                 data.propose(value).await?;
+            } else {
+                data.propose_request().await?;
             }
         }
 
+        data.serve_clients();
         data.tick();
+        data.check_timers();
+        data.answer_reads();
+        data.answer_clients();
+        data.answer_peers();
 
         data.flush_log().await?;
     }
@@ -6,37 +6,128 @@ pub mod dist_types {
 use dist_types::PeerId;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{self, AsyncWriteExt},
+    io::{self, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
 };
 
-use crate::state::paxos::{Proposal, ProposalNum};
+use crate::state::{
+    paxos::{Ballot, PaxosStage, Proposal, Slot, Value},
+    raft::Term,
+};
 
 // Type of message being sent
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     // Sent by a peer once it establishes a connection
     Alive,
-    Prepare(Proposal),
-    PrepareAck(Option<Proposal>),
-    Accept(Proposal),
-    AcceptAck { min_proposal: ProposalNum },
-    Chosen(Proposal),
+    // Every paxos message is tagged with the log slot it is deciding.
+    // Prepares instead carry a watermark, and promise every slot from there up
+    Prepare(Slot, Proposal),
+    PrepareAck(Slot, Ballot, Vec<(Slot, Proposal)>),
+    // Sent instead of an ack when the acceptor already promised a higher ballot
+    PrepareNack {
+        watermark: Slot,
+        promised: Ballot,
+    },
+    Accept(Slot, Proposal),
+    AcceptAck(Slot, Ballot),
+    AcceptNack {
+        slot: Slot,
+        promised: Ballot,
+    },
+    Chosen(Slot, Proposal),
+    // A learner asking acceptors and other learners for every slot from here up
+    LearnQuery(Slot),
+    // An acceptor's answer, with everything it has accepted from that slot up
+    LearnReply(Vec<(Slot, Proposal)>),
+    // A peer with no learner in the stage asking for its decision, answered
+    // with a Chosen for slot 0 by anyone who has seen it
+    DecisionQuery,
+    // A piece of the snapshot of every slot below `upto`, starting `offset`
+    // bytes into it, for a learner asking about slots we already threw away
+    SnapshotChunk {
+        upto: Slot,
+        offset: u64,
+        total: u64,
+        data: Vec<u8>,
+    },
+    // Asks for the snapshot of every slot below `upto` from `offset` on, so a
+    // transfer picks up where it stopped
+    SnapshotRequest {
+        upto: Slot,
+        offset: u64,
+    },
+    // An acceptor turning down a prepare or accept below the slot it has
+    // compacted up to, since everything there is decided
+    Compacted(Slot),
+    // The stable leader asking its acceptors for a lease under its ballot.
+    // The number tells one renewal from the next
+    Lease(Ballot, u64),
+    // An acceptor granting it, after which it turns down every other
+    // proposer's prepare until the lease runs out
+    LeaseGrant(Ballot, u64),
+    // The stable leader checking it still leads before serving reads, without
+    // leases, and an acceptor that hasn't promised anyone above it saying so
+    Heartbeat(Ballot, u64),
+    HeartbeatAck(Ballot, u64),
+    // Fast Paxos. The coordinator tells acceptors they can take any value into
+    // an empty slot from here up under this ballot
+    AnyValue(Slot, Ballot),
+    // A value sent straight to the acceptors, or handed on to the coordinator
+    // by an acceptor with no fast round open
+    FastPropose(Value),
+    // An acceptor's vote in a fast round, sent to the coordinator
+    FastAccepted(Slot, Proposal),
+    // Raft. A candidate asking for votes, with how long its log is and the
+    // term of its last entry
+    RequestVote {
+        term: Term,
+        log_len: Slot,
+        last_term: Term,
+    },
+    Vote {
+        term: Term,
+        granted: bool,
+    },
+    // Entries to go after the first `prev_len` ones in the follower's log, the
+    // last of which has to be from `prev_term`. Empty ones are heartbeats
+    AppendEntries {
+        term: Term,
+        prev_len: Slot,
+        prev_term: Term,
+        entries: Vec<Proposal>,
+        commit: Slot,
+    },
+    // How much of the leader's log the follower holds now, or where the leader
+    // should retry from when it doesn't match
+    AppendReply {
+        term: Term,
+        success: bool,
+        log_len: Slot,
+    },
+    // A value for the raft leader to append
+    ClientRequest(Value),
+    // A line an outside client sent a peer with no proposer, handed on to the
+    // running stage's proposer, and what that proposer answered
+    ClientLine(u64, String),
+    ClientAnswer(u64, String),
 }
 impl Message {
     /// Prints according to project specs for sending and receiving messages
     pub fn paxos_print(&self, id: PeerId, sent: bool, prop: &Proposal) {
-        let message_type = match self {
-            Self::Prepare(_) => "prepare",
-            Self::PrepareAck(_) => "prepare_ack",
-            Self::Accept(_) => "accept",
-            Self::AcceptAck { .. } => "accept_ack",
-            Self::Chosen(_) => "chose",
+        let (message_type, slot) = match self {
+            Self::Prepare(slot, _) => ("prepare", slot),
+            Self::PrepareAck(slot, ..) => ("prepare_ack", slot),
+            Self::PrepareNack { watermark, .. } => ("prepare_nack", watermark),
+            Self::Accept(slot, _) => ("accept", slot),
+            Self::AcceptAck(slot, _) => ("accept_ack", slot),
+            Self::AcceptNack { slot, .. } => ("accept_nack", slot),
+            Self::Chosen(slot, _) => ("chose", slot),
+            Self::FastAccepted(slot, _) => ("fast_accepted", slot),
             _ => return,
-        }
-        .to_string();
+        };
 
-        let action = if let Self::Chosen(_) = self {
+        let action = if let Self::Chosen(..) = self {
             "chose"
         } else if sent {
             "sent"
@@ -45,27 +136,41 @@ impl Message {
         }
         .to_string();
 
-        eprint!(
-            "{{\"peer_id\": {id}, \"action\": \"{action}\", \"message_type\": \"{message_type}\", \"message_value\": \"{}\", \"proposal_num\": {}}}\n",
-            prop.value, prop.num
+        eprintln!(
+            "{{\"peer_id\": {id}, \"action\": \"{action}\", \"message_type\": \"{message_type}\", \"message_value\": {}, \"proposal_num\": {}, \"proposer\": {}, \"slot\": {slot}}}",
+            prop.value.json(),
+            prop.num.round,
+            prop.num.proposer
         );
     }
 }
 
-// Message with an address
+// Message with an address. The stage says which of the recipient's roles it is for
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Letter {
     from: PeerId,
+    stage: PaxosStage,
     contents: Message,
     to: PeerId,
 }
 impl Letter {
+    /// Writes the letter prefixed with its length, since back to back
+    /// letters can arrive in the same read
     pub async fn send(&self, sender: &mut TcpStream) -> io::Result<()> {
         let buffer = bincode::serialize(self).expect("Message is serializable");
+        sender.write_u32(buffer.len() as u32).await?;
         sender.write_all(&buffer).await?;
         Ok(())
     }
 
+    /// Reads exactly one length prefixed letter off the stream
+    pub async fn receive(receiver: &mut TcpStream) -> io::Result<Self> {
+        let len = receiver.read_u32().await?;
+        let mut buffer = vec![0; len as usize];
+        receiver.read_exact(&mut buffer).await?;
+        bincode::deserialize(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
     pub fn message(&self) -> &Message {
         &self.contents
     }
@@ -74,6 +179,10 @@ impl Letter {
         self.from
     }
 
+    pub fn stage(&self) -> PaxosStage {
+        self.stage
+    }
+
     pub fn to(&self) -> PeerId {
         self.to
     }
@@ -81,10 +190,17 @@ impl Letter {
 
 impl From<(PeerId, Message, PeerId)> for Letter {
     fn from(value: (PeerId, Message, PeerId)) -> Self {
+        (value.0, 0, value.1, value.2).into()
+    }
+}
+
+impl From<(PeerId, PaxosStage, Message, PeerId)> for Letter {
+    fn from(value: (PeerId, PaxosStage, Message, PeerId)) -> Self {
         Self {
             from: value.0,
-            contents: value.1,
-            to: value.2,
+            stage: value.1,
+            contents: value.2,
+            to: value.3,
         }
     }
 }
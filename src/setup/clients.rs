@@ -0,0 +1,69 @@
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+use super::hostsfile::PeerList;
+const CLIENT_PORT: &str = "6970";
+
+/// One line an outside client sent, and where its answer goes
+pub struct Request {
+    pub line: String,
+    pub reply: UnboundedSender<String>,
+}
+
+// Takes values from outside clients, apart from the peers on `TCP_PORT`. A
+// client writes one value per line and gets one JSON line back for each
+pub struct Clients {
+    requests: UnboundedReceiver<Request>,
+}
+
+/// Reads requests off a client until it hangs up, and writes back whatever
+/// replies come for it
+fn serve(sock: TcpStream, requests: UnboundedSender<Request>) {
+    tokio::spawn(async move {
+        let (read, mut write) = sock.into_split();
+        let (reply, mut replies) = unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(line) = replies.recv().await {
+                if write
+                    .write_all(format!("{line}\n").as_bytes())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(read).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let request = Request {
+                line,
+                reply: reply.clone(),
+            };
+            if requests.send(request).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+impl Clients {
+    pub async fn new(list: &PeerList) -> io::Result<Self> {
+        let listener = TcpListener::bind(format!("{}:{}", list.hostname(), CLIENT_PORT)).await?;
+        let (send, requests) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                serve(sock, send.clone());
+            }
+        });
+        Ok(Self { requests })
+    }
+
+    /// Polls for requests
+    pub fn check_requests(&mut self) -> Option<Request> {
+        self.requests.try_recv().ok()
+    }
+}
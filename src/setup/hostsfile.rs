@@ -1,28 +1,58 @@
 use indexmap::IndexMap;
 use nom::{
+    branch::alt,
     bytes::complete::{tag, take_until},
     character::{complete::alpha1, complete::digit1},
-    combinator::map_res,
+    combinator::{map, map_res, opt},
     multi::separated_list1,
-    sequence::separated_pair,
+    sequence::{preceded, separated_pair},
     IResult, Parser,
 };
-use std::{collections::VecDeque, fs::File, io::Read, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use crate::{
     messaging::dist_types::PeerId,
-    state::paxos::{Accepting, Learning, PaxosRole, PaxosStage, Proposing},
+    state::{
+        fast::{self, Coordinating, Submitting},
+        paxos::{Accepting, Learning, PaxosRole, PaxosStage, Proposing, Quorums, Weight},
+        raft::{Client, RaftRole, Server},
+    },
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Peers that joined in a reconfiguration with their hostnames, and peers it
+/// left without any role
+pub type Membership = (Vec<(PeerId, String)>, Vec<PeerId>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Role {
     Proposer(u32),
     Acceptor(u32),
     Learner(u32),
 }
-fn parse_roleid(input: &str) -> IResult<&str, Role> {
+impl Role {
+    pub fn stage(self) -> PaxosStage {
+        match self {
+            Role::Proposer(n) | Role::Acceptor(n) | Role::Learner(n) => n,
+        }
+    }
+}
+/// A role, optionally followed by `*N` to make an acceptor's vote count N times
+fn parse_roleid(input: &str) -> IResult<&str, (Role, Weight)> {
     let (input, role_type) = alpha1(input)?;
     let (input, id) = map_res(digit1, |s: &str| s.parse::<u32>()).parse(input)?;
+    let (input, weight) = opt(preceded(tag("*"), parse_count)).parse(input)?;
+    if weight.is_some() && role_type != "acceptor" {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
 
     let role = match role_type {
         "proposer" => Role::Proposer,
@@ -36,44 +66,231 @@ fn parse_roleid(input: &str) -> IResult<&str, Role> {
         }
     }(id);
 
-    Ok((input, role))
+    Ok((input, (role, weight.unwrap_or(1))))
+}
+
+fn parse_roles(input: &str) -> IResult<&str, Vec<(Role, Weight)>> {
+    separated_list1(tag(","), parse_roleid).parse(input)
+}
+
+/// Settings for a whole stage, given on a `stageN:` line instead of a peer's roles.
+/// Anything left out falls back to plain majorities. `fast` (or giving a fast
+/// quorum with `fq=N`) runs the stage as Fast Paxos. `deadline=MS` has the
+/// stage's proposers settle it with a no-op if it decided nothing that long after
+/// it started, and `seed` has this stage's proposers propose the previous
+/// stage's decision first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StageConfig {
+    phase1: Option<Weight>,
+    phase2: Option<Weight>,
+    fast: bool,
+    fast_quorum: Option<Weight>,
+    deadline: Option<Duration>,
+    seed: bool,
+}
+
+enum StageSetting {
+    Phase1(Weight),
+    Phase2(Weight),
+    Fast,
+    FastQuorum(Weight),
+    Deadline(Duration),
+    Seed,
+}
+fn parse_count(input: &str) -> IResult<&str, Weight> {
+    map_res(digit1, |s: &str| s.parse::<Weight>()).parse(input)
+}
+fn parse_stage_setting(input: &str) -> IResult<&str, StageSetting> {
+    alt((
+        map(preceded(tag("q1="), parse_count), StageSetting::Phase1),
+        map(preceded(tag("q2="), parse_count), StageSetting::Phase2),
+        map(preceded(tag("fq="), parse_count), StageSetting::FastQuorum),
+        map(tag("fast"), |_| StageSetting::Fast),
+        map(preceded(tag("deadline="), parse_count), |ms| {
+            StageSetting::Deadline(Duration::from_millis(ms))
+        }),
+        map(tag("seed"), |_| StageSetting::Seed),
+    ))
+    .parse(input)
+}
+fn parse_stage_config(input: &str) -> IResult<&str, (PaxosStage, StageConfig)> {
+    let (input, stage) = preceded(
+        tag("stage"),
+        map_res(digit1, |s: &str| s.parse::<PaxosStage>()),
+    )
+    .parse(input)?;
+    let (input, settings) =
+        preceded(tag(":"), separated_list1(tag(","), parse_stage_setting)).parse(input)?;
+
+    let mut config = StageConfig::default();
+    for setting in settings {
+        match setting {
+            StageSetting::Phase1(q) => config.phase1 = Some(q),
+            StageSetting::Phase2(q) => config.phase2 = Some(q),
+            StageSetting::Fast => config.fast = true,
+            StageSetting::FastQuorum(q) => {
+                config.fast = true;
+                config.fast_quorum = Some(q);
+            }
+            StageSetting::Deadline(deadline) => config.deadline = Some(deadline),
+            StageSetting::Seed => config.seed = true,
+        }
+    }
+    Ok((input, (stage, config)))
 }
 
-fn parse_roles(input: &str) -> IResult<&str, VecDeque<Role>> {
-    separated_list1(tag(","), parse_roleid)
-        .parse(input)
-        .map(|(input, vec)| (input, vec.into()))
+struct Hostsfile {
+    peer_names: IndexMap<String, VecDeque<Role>>,
+    stages: HashMap<PaxosStage, StageConfig>,
+    weights: HashMap<(PeerId, PaxosStage), Weight>,
 }
-fn make_roles<'a>(input: &'a str) -> IResult<&'a str, IndexMap<String, VecDeque<Role>>> {
+fn make_roles(input: &str) -> IResult<&str, Hostsfile> {
     let mut key_values = separated_pair(take_until(":"), tag(":"), parse_roles);
-    let mut out = IndexMap::new();
+    let mut out = Hostsfile {
+        peer_names: IndexMap::new(),
+        stages: HashMap::new(),
+        weights: HashMap::new(),
+    };
     for line in input.lines() {
+        if let Ok((_input, (stage, config))) = parse_stage_config(line) {
+            out.stages.insert(stage, config);
+            continue;
+        }
         let (_input, (peer_name, roles)) = key_values.parse(line)?;
-        out.insert(peer_name.into(), roles);
+        let id = out.peer_names.len() + 1;
+        for (role, weight) in &roles {
+            if let Role::Acceptor(stage) = role {
+                out.weights.insert((id, *stage), *weight);
+            }
+        }
+        out.peer_names.insert(
+            peer_name.into(),
+            roles.into_iter().map(|(r, _)| r).collect(),
+        );
     }
     Ok((input, out))
 }
 
 /// Helper to keep track of whos who
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PeerList {
     peer_names: IndexMap<String, VecDeque<Role>>,
+    stages: HashMap<PaxosStage, StageConfig>,
+    weights: HashMap<(PeerId, PaxosStage), Weight>,
     hostname: String,
 }
 impl PeerList {
-    pub fn load(path: PathBuf) -> std::io::Result<Self> {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
         let hostname = hostname::get()?.into_string().expect("Converted");
-        let peer_names = File::open(path).map(|mut f| {
+        let hostsfile = File::open(path).map(|mut f| {
             let mut names = String::new();
             let _bytes = f.read_to_string(&mut names).expect("Can read hostsfile");
             let (_, pn) = make_roles(&names).expect("Valid lists");
             pn
         })?;
 
-        Ok(PeerList {
-            peer_names,
+        let list = PeerList {
+            peer_names: hostsfile.peer_names,
+            stages: hostsfile.stages,
+            weights: hostsfile.weights,
             hostname,
-        })
+        };
+        for stage in list.stages.keys() {
+            list.check(*stage)?;
+        }
+        Ok(list)
+    }
+
+    /// Makes sure a stage's quorums still overlap the way they have to
+    fn check(&self, stage: PaxosStage) -> io::Result<()> {
+        let q = self.quorums(stage);
+        if q.phase1.max(q.phase2) > q.total()
+            || q.phase1.min(q.phase2) == 0
+            || q.phase1 + q.phase2 <= q.total()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "stage{stage}: q1={} and q2={} don't overlap across {} acceptor votes",
+                    q.phase1,
+                    q.phase2,
+                    q.total()
+                ),
+            ));
+        }
+        // any two fast quorums and a phase 1 quorum have to share a vote
+        if let Some(f) = q.fast {
+            if f > q.total() || 2 * f + q.phase1 <= 2 * q.total() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "stage{stage}: fq={f} is too small for q1={} across {} acceptor votes",
+                        q.phase1,
+                        q.total()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `members` the acceptors of `stage`, with their weights. Hostnames
+    /// we haven't heard of join as new peers after everyone else, so every
+    /// peer hands out the same ids. Refused (with nothing changed) if the
+    /// stage's quorums wouldn't overlap anymore. Hands back the peers that
+    /// joined, and the ones left without any role
+    pub fn reconfigure(
+        &mut self,
+        stage: PaxosStage,
+        members: &[(String, Weight)],
+    ) -> io::Result<Membership> {
+        let mut next = self.clone();
+        let mut joined = Vec::new();
+        let mut retired = Vec::new();
+        for (index, roles) in next.peer_names.values_mut().enumerate() {
+            let had_roles = !roles.is_empty();
+            roles.retain(|role| *role != Role::Acceptor(stage));
+            next.weights.remove(&(index + 1, stage));
+            if had_roles && roles.is_empty() {
+                retired.push(index + 1);
+            }
+        }
+        for (name, weight) in members {
+            let id = match next.peer_names.get_index_of(name) {
+                Some(index) => index + 1,
+                None => {
+                    next.peer_names.insert(name.clone(), VecDeque::new());
+                    joined.push((next.peer_names.len(), name.clone()));
+                    next.peer_names.len()
+                }
+            };
+            next.peer_names[id - 1].push_back(Role::Acceptor(stage));
+            next.weights.insert((id, stage), *weight);
+            retired.retain(|peer| *peer != id);
+        }
+        next.check(stage)?;
+        *self = next;
+        Ok((joined, retired))
+    }
+
+    /// Quorum sizes for a stage, in acceptor votes. Majorities of the total
+    /// weight unless the hostsfile says otherwise
+    pub fn quorums(&self, stage: PaxosStage) -> Quorums {
+        let weights = self
+            .acceptors(stage)
+            .into_iter()
+            .map(|id| (id, self.weights.get(&(id, stage)).copied().unwrap_or(1)))
+            .collect();
+        let mut quorums = Quorums::majority(weights);
+        if let Some(config) = self.stages.get(&stage) {
+            quorums.phase1 = config.phase1.unwrap_or(quorums.phase1);
+            quorums.phase2 = config.phase2.unwrap_or(quorums.phase2);
+            if config.fast {
+                // left unset when q1 is too big for any, which `check` refuses
+                quorums.fast = config.fast_quorum.or_else(|| fast::fast_quorum(&quorums));
+            }
+        }
+        quorums
     }
 
     pub fn hostname(&self) -> &str {
@@ -104,6 +321,47 @@ impl PeerList {
             .collect()
     }
 
+    /// Every stage anyone holds a role in or that has a `stageN:` line, in the
+    /// order they run
+    pub fn stages(&self) -> Vec<PaxosStage> {
+        let mut stages: Vec<PaxosStage> = self
+            .peer_names
+            .values()
+            .flatten()
+            .map(|role| role.stage())
+            .chain(self.stages.keys().copied())
+            .collect();
+        stages.sort();
+        stages.dedup();
+        stages
+    }
+
+    /// How long a stage gets before its proposers settle it with a no-op
+    pub fn deadline(&self, stage: PaxosStage) -> Option<Duration> {
+        self.stages.get(&stage).and_then(|config| config.deadline)
+    }
+
+    /// True if a stage's proposers start from the previous stage's decision
+    pub fn seeded(&self, stage: PaxosStage) -> bool {
+        self.stages.get(&stage).is_some_and(|config| config.seed)
+    }
+
+    /// The first proposer of a stage. In a fast stage it coordinates the
+    /// rounds, and every other proposer just submits values
+    pub fn coordinator(&self, num: PaxosStage) -> Option<PeerId> {
+        self.peer_names
+            .values()
+            .position(|roles| roles.contains(&Role::Proposer(num)))
+            .map(|index| index + 1)
+    }
+
+    /// The hostname of a peer
+    pub fn name(&self, id: PeerId) -> Option<&str> {
+        self.peer_names
+            .get_index(id.checked_sub(1)?)
+            .map(|(name, _)| name.as_str())
+    }
+
     /// Returns iterator of all peer Ids and their names
     pub fn ids_and_names(&self) -> impl Iterator<Item = (PeerId, &String)> {
         self.peer_names
@@ -118,8 +376,13 @@ impl PeerList {
         self.peer_names.len() - 1
     }
 
+    /// Every peer, us included. Our own copy goes to our other roles
+    pub fn everyone(&self) -> Vec<PeerId> {
+        (1..=self.peer_names.len()).collect()
+    }
+
+    /// Everyone a learner can ask about decided slots in a stage
     pub fn acceptors_and_learners(&self, num: PaxosStage) -> Vec<PeerId> {
-        // How could something so right feel so wrong
         self.peer_names
             .iter()
             .enumerate()
@@ -132,19 +395,200 @@ impl PeerList {
             .collect()
     }
 
-    pub fn paxos_role(&self) -> PaxosRole {
-        let initial_role = self
-            .peer_names
+    /// Every role this peer holds, in the order the hostsfile lists them
+    pub fn roles(&self) -> Vec<Role> {
+        self.peer_names
             .get(&self.hostname)
             .expect("Should have roles")
-            .get(0)
-            .unwrap();
-        match initial_role {
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Builds the instance for one of this peer's roles. Acceptors given a data
+    /// directory recover whatever state they had persisted there for their
+    /// stage. Proposers of classic stages pipeline up to `window` slots, and
+    /// take leases to serve reads on if `leases` is set
+    pub fn paxos_role(
+        &self,
+        role: Role,
+        data_dir: Option<&Path>,
+        window: usize,
+        leases: bool,
+    ) -> io::Result<PaxosRole> {
+        Ok(match role {
+            Role::Proposer(stage) if self.quorums(stage).fast.is_some() => {
+                if self.coordinator(stage) == Some(self.id()) {
+                    PaxosRole::Coord(Box::new(Coordinating::new(
+                        self.quorums(stage),
+                        stage,
+                        self.id(),
+                    )))
+                } else {
+                    PaxosRole::Submit(Submitting::new(self.quorums(stage), stage))
+                }
+            }
+            Role::Proposer(stage) => PaxosRole::Prop(Box::new(Proposing::new(
+                self.quorums(stage),
+                stage,
+                self.id(),
+                window,
+                leases,
+            ))),
+            Role::Learner(stage) => PaxosRole::Learn(Learning::new(self.quorums(stage), stage)),
+            Role::Acceptor(stage) => match data_dir {
+                Some(dir) => PaxosRole::Acc(Accepting::recover(dir, stage)?),
+                None => PaxosRole::Acc(Accepting::new(stage)),
+            },
+        })
+    }
+
+    /// Builds one of this peer's roles under Raft, where a stage's acceptors
+    /// are its servers and its proposers are clients. Servers given a data
+    /// directory recover their term, vote and log from it
+    pub fn raft_role(&self, role: Role, data_dir: Option<&Path>) -> io::Result<RaftRole> {
+        Ok(match role {
             Role::Proposer(stage) => {
-                PaxosRole::Prop(Proposing::new(self.acceptors(*stage).len(), *stage))
+                RaftRole::Client(Box::new(Client::new(self.quorums(stage), stage)))
             }
-            Role::Learner(_) => PaxosRole::Learn(Learning::default()),
-            Role::Acceptor(_) => PaxosRole::Acc(Accepting::default()),
-        }
+            Role::Learner(stage) => {
+                RaftRole::Learn(Box::new(Learning::new(self.quorums(stage), stage)))
+            }
+            Role::Acceptor(stage) => RaftRole::Server(Box::new(match data_dir {
+                Some(dir) => Server::recover(dir, self.quorums(stage), stage, self.id())?,
+                None => Server::new(self.quorums(stage), stage, self.id()),
+            })),
+        })
+    }
+}
+
+/// The peer list `hostsfile` gives peer1, for tests elsewhere to build on
+#[cfg(test)]
+pub fn peers(hostsfile: &str) -> PeerList {
+    let (_, hostsfile) = make_roles(hostsfile).expect("Valid lists");
+    PeerList {
+        peer_names: hostsfile.peer_names,
+        stages: hostsfile.stages,
+        weights: hostsfile.weights,
+        hostname: "peer1".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCEPTORS: &str = "peer1:proposer1\npeer2:acceptor1\npeer3:acceptor1\npeer4:acceptor1\n";
+
+    #[test]
+    fn check_wants_phase_quorums_to_overlap() {
+        let q = peers(ACCEPTORS).quorums(1);
+        assert_eq!((q.phase1, q.phase2), (2, 2));
+        assert!(peers(ACCEPTORS).check(1).is_ok());
+        assert!(peers(&format!("stage1:q1=3,q2=1\n{ACCEPTORS}"))
+            .check(1)
+            .is_ok());
+        assert!(peers(&format!("stage1:q1=1,q2=3\n{ACCEPTORS}"))
+            .check(1)
+            .is_ok());
+
+        assert!(peers(&format!("stage1:q1=1,q2=2\n{ACCEPTORS}"))
+            .check(1)
+            .is_err());
+        assert!(peers(&format!("stage1:q1=4,q2=1\n{ACCEPTORS}"))
+            .check(1)
+            .is_err());
+        assert!(peers(&format!("stage1:q1=3,q2=0\n{ACCEPTORS}"))
+            .check(1)
+            .is_err());
+    }
+
+    #[test]
+    fn check_counts_weights() {
+        let weighted = "peer1:proposer1\npeer2:acceptor1*3\npeer3:acceptor1\npeer4:acceptor1\n";
+        assert_eq!(peers(weighted).quorums(1).total(), 5);
+        assert!(peers(weighted).check(1).is_ok());
+        // 3 of 5 votes can be peer2 alone, and peers 3 and 4 together are 2
+        assert!(peers(&format!("stage1:q1=3,q2=2\n{weighted}"))
+            .check(1)
+            .is_err());
+        assert!(peers(&format!("stage1:q1=3,q2=3\n{weighted}"))
+            .check(1)
+            .is_ok());
+    }
+
+    #[test]
+    fn check_limits_the_fast_quorum() {
+        assert_eq!(
+            peers(&format!("stage1:fast\n{ACCEPTORS}")).quorums(1).fast,
+            Some(3)
+        );
+        assert!(peers(&format!("stage1:fast\n{ACCEPTORS}")).check(1).is_ok());
+        assert!(peers(&format!("stage1:fq=3\n{ACCEPTORS}")).check(1).is_ok());
+
+        assert!(peers(&format!("stage1:fq=2\n{ACCEPTORS}"))
+            .check(1)
+            .is_err());
+        assert!(peers(&format!("stage1:fq=4\n{ACCEPTORS}"))
+            .check(1)
+            .is_err());
+        // a bigger phase 1 quorum lets the fast one shrink
+        assert!(peers(&format!("stage1:q1=3,q2=1,fq=2\n{ACCEPTORS}"))
+            .check(1)
+            .is_ok());
+    }
+
+    #[test]
+    fn check_refuses_a_q1_too_big_for_a_fast_quorum() {
+        assert!(peers(&format!("stage1:q1=7,fast\n{ACCEPTORS}"))
+            .check(1)
+            .is_err());
+        // a fast stage nobody accepts in
+        assert!(peers(&format!("stage2:fast\n{ACCEPTORS}"))
+            .check(2)
+            .is_err());
+    }
+
+    #[test]
+    fn reconfigure_refuses_shrinking_a_fast_stage_under_q1() {
+        let mut list = peers(&format!("stage1:q1=3,fast\n{ACCEPTORS}"));
+        assert!(list.check(1).is_ok());
+        assert!(list.reconfigure(1, &[("peer2".into(), 1)]).is_err());
+        assert_eq!(list.acceptors(1), [2, 3, 4]);
+        assert_eq!(list.quorums(1).fast, Some(2));
+    }
+
+    #[test]
+    fn reconfigure_hands_out_ids_to_new_peers_and_retires_idle_ones() {
+        let mut list = peers(
+            "peer1:proposer1,proposer2\npeer2:acceptor1,acceptor2\npeer3:acceptor1,acceptor2\npeer4:acceptor2\n",
+        );
+        let members = [
+            ("peer3".into(), 1),
+            ("peer5".into(), 2),
+            ("peer6".into(), 1),
+        ];
+        let (joined, retired) = list.reconfigure(2, &members).expect("Quorums overlap");
+        assert_eq!(joined, [(5, "peer5".to_string()), (6, "peer6".to_string())]);
+        // peer2 still accepts in stage 1
+        assert_eq!(retired, [4]);
+        assert_eq!(list.acceptors(2), [3, 5, 6]);
+        assert_eq!(list.acceptors(1), [2, 3]);
+        assert_eq!(list.quorums(2).total(), 4);
+    }
+
+    #[test]
+    fn reconfigure_refuses_quorums_that_stop_overlapping_and_changes_nothing() {
+        let mut list = peers(&format!("stage1:q1=2,q2=2\n{ACCEPTORS}"));
+        let members: Vec<(String, Weight)> =
+            (2..=6).map(|peer| (format!("peer{peer}"), 1)).collect();
+        assert!(list.reconfigure(1, &members).is_err());
+        assert_eq!(list.acceptors(1), [2, 3, 4]);
+        assert_eq!(list.everyone().len(), 4);
+
+        // 2 + 2 votes is still more than the 3 these two have between them
+        let members: Vec<(String, Weight)> = vec![("peer2".into(), 2), ("peer3".into(), 1)];
+        assert!(list.reconfigure(1, &members).is_ok());
+        assert_eq!(list.acceptors(1), [2, 3]);
     }
 }
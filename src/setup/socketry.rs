@@ -1,9 +1,14 @@
 use core::panic;
-use std::{collections::HashMap, future::Future, thread::sleep, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    thread::sleep,
+    time::Duration,
+};
 
-use tokio::io::{self, AsyncReadExt};
+use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 use crate::messaging::{dist_types::PeerId, Letter, Message};
 
@@ -15,6 +20,15 @@ pub struct Nexus {
     rec_incoming: UnboundedReceiver<Letter>,
     // Streams made by TcpStream::connect
     outgoing: HashMap<PeerId, TcpStream>,
+    // Peers whose connection broke. Anything still addressed to them is dropped,
+    // same as if the network lost it
+    lost: HashSet<PeerId>,
+    // Peers added after startup that we are still getting through to, and
+    // where their streams turn up once we do. Their mail is dropped until then
+    connecting: HashSet<PeerId>,
+    connected: UnboundedReceiver<(PeerId, TcpStream)>,
+    on_connect: UnboundedSender<(PeerId, TcpStream)>,
+    id: PeerId,
 }
 
 async fn attempt_op<F, Fut, Socket>(op: F, host: &str) -> Socket
@@ -30,6 +44,23 @@ where
         }
     }
 }
+/// Checks a freshly accepted stream for signs of life, then polls it for all eternity
+fn listen(mut sock: TcpStream, send: UnboundedSender<Letter>) {
+    tokio::spawn(async move {
+        // shouldnt get any other message first
+        match Letter::receive(&mut sock).await {
+            Ok(letter) if matches!(letter.message(), Message::Alive) => {}
+            _ => return,
+        }
+        // a failed read means the peer hung up
+        while let Ok(l) = Letter::receive(&mut sock).await {
+            if send.send(l).is_err() {
+                break;
+            }
+        }
+    });
+}
+
 impl Nexus {
     pub async fn new(list: &PeerList) -> Self {
         let mut outgoing = HashMap::new();
@@ -61,48 +92,82 @@ impl Nexus {
 
         // Check for signs of life off our connections and give their own threads for polling
         let (send, rec_incoming) = unbounded_channel();
-        for mut sock in anon_socks {
-            let mut buf = [0; 1024];
-            if let Ok(bytes_read) = sock.read(&mut buf).await {
-                let letter: Letter =
-                    bincode::deserialize(&buf[..bytes_read]).expect("Deserializeable");
-                // shouldnt get any other message
-                assert!(matches!(letter.message(), Message::Alive));
-
-                // once we know our peer is alive, move the stream into its own async
-                // thread where you can poll it for all eternity
-                let send = send.clone();
-                tokio::spawn(async move {
-                    let mut buffer = [0; 1024];
-
-                    loop {
-                        if let Ok(bytes_read) = sock.read(&mut buffer).await {
-                            assert!(bytes_read > 0);
-                            let l: Letter =
-                                bincode::deserialize(&buffer[..bytes_read]).expect("Full Message");
-                            send.send(l).expect("Successful send");
-                        }
-                    }
-                });
-            }
+        for sock in anon_socks {
+            listen(sock, send.clone());
         }
+        // peers added by a reconfiguration show up later
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                listen(sock, send.clone());
+            }
+        });
 
+        let (on_connect, connected) = unbounded_channel();
         Self {
             outgoing,
             rec_incoming,
+            lost: HashSet::new(),
+            connecting: HashSet::new(),
+            connected,
+            on_connect,
+            id: list.id(),
+        }
+    }
+
+    /// Starts connecting to a peer that joined after startup. Letters for it
+    /// are dropped until the connection is up
+    pub fn connect(&mut self, id: PeerId, hostname: &str) {
+        if self.outgoing.contains_key(&id) || !self.connecting.insert(id) {
+            return;
         }
+        self.lost.remove(&id);
+        let addr = format!("{}:{}", hostname, TCP_PORT);
+        let i_am_alive: Letter = (self.id, Message::Alive, id).into();
+        let on_connect = self.on_connect.clone();
+        tokio::spawn(async move {
+            let mut sock = loop {
+                match TcpStream::connect(&addr).await {
+                    Ok(sock) => break sock,
+                    Err(_) => tokio::time::sleep(Duration::from_secs(2)).await,
+                }
+            };
+            if i_am_alive.send(&mut sock).await.is_ok() {
+                let _ = on_connect.send((id, sock));
+            }
+        });
+    }
+
+    /// Hangs up on a peer that no longer holds any role
+    pub fn disconnect(&mut self, id: PeerId) {
+        self.outgoing.remove(&id);
+        self.connecting.remove(&id);
+        self.lost.insert(id);
     }
 
     /// Polls for letters
     pub fn check_mailbox(&mut self) -> Option<Letter> {
+        while let Ok((id, sock)) = self.connected.try_recv() {
+            if self.connecting.remove(&id) {
+                self.outgoing.insert(id, sock);
+            }
+        }
         self.rec_incoming.try_recv().ok()
     }
 
     pub async fn send_letter(&mut self, letter: Letter) -> io::Result<()> {
         let to = letter.to();
         if let Some(sock) = self.outgoing.get_mut(&to) {
-            letter.send(sock).await?;
-        } else {
+            if let Err(e) = letter.send(sock).await {
+                if !matches!(
+                    e.kind(),
+                    io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+                ) {
+                    return Err(e);
+                }
+                self.outgoing.remove(&to);
+                self.lost.insert(to);
+            }
+        } else if !self.lost.contains(&to) && !self.connecting.contains(&to) {
             panic!("DOESNT EXIST");
         }
         Ok(())
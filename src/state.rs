@@ -1,127 +1,713 @@
-use std::{collections::VecDeque, thread::sleep, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
-use paxos::{Chooser, PaxosRole, Value};
-use tokio::io;
+use batch::Batcher;
+use engine::{ConsensusEngine, Outbox, ReadId};
+use kv::{ClientId, LastRequest};
+use paxos::{Ballot, PaxosStage, Proposal, Slot, Value, Weight};
+use stages::Schedule;
+use tokio::{
+    io,
+    sync::mpsc::{error::TryRecvError, unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
 
 use crate::{
+    args::Engine,
     messaging::{dist_types::PeerId, Message},
-    setup::{hostsfile::PeerList, socketry::Nexus},
+    setup::{
+        clients::{Clients, Request},
+        hostsfile::{PeerList, Role},
+        socketry::Nexus,
+    },
 };
+pub mod batch;
+pub mod engine;
+pub mod fast;
+pub mod kv;
 pub mod paxos;
+pub mod raft;
+pub mod snapshot;
+pub mod stages;
+pub mod value;
+pub mod wal;
+
+/// How often a peer with no learner in the running stage asks around for
+/// its decision
+const DECISION_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What this peer's engine instances get built with, as picked on the
+/// command line
+pub struct Settings {
+    pub engine: Engine,
+    pub data_dir: Option<PathBuf>,
+    // Most slots a Multi-Paxos leader keeps in the accept phase at once
+    pub window: usize,
+    // Whether that leader takes leases to serve reads under, instead of
+    // confirming it still leads for each one
+    pub leases: bool,
+}
+
+/// One of this peer's roles, running in its own stage
+struct Instance {
+    role: Role,
+    engine: Box<dyn ConsensusEngine>,
+}
+impl Instance {
+    fn stage(&self) -> PaxosStage {
+        self.role.stage()
+    }
+}
 
 pub struct Data {
     // Non paxos
     peer_list: PeerList,
     nexus: Nexus,
 
-    // Paxos stuff
-    role: PaxosRole,
-    // Message to send and who its going to
-    log: VecDeque<(Message, Vec<PeerId>)>,
+    // An engine for every (role, stage) pair we hold, in whatever protocol
+    // was picked with `-e`. Reconfigurations add and drop acceptors, so new
+    // ones get built from the same settings
+    instances: Vec<Instance>,
+    settings: Settings,
+    // Message to send, who its going to, and which of our roles sent it
+    log: VecDeque<(Role, Message, Vec<PeerId>)>,
+    // Messages addressed to ourselves, waiting for our other instances in
+    // that stage to pick them up
+    loopback: VecDeque<(Role, Message)>,
+    // Which stages have started, and what each one decided
+    schedule: Schedule,
+    // Proposers that already took a value, so anything else they take lands
+    // past their stage's first slot
+    opened: HashSet<Role>,
+    // When we next ask for the running stage's decision, if we have to
+    next_decision_query: Option<Instant>,
+    // Anything bigger than this is refused before it gets proposed
+    max_value_size: usize,
+
+    // Outside clients, their values waiting for one of our proposers, and
+    // the ones proposed that we still owe an answer once they are chosen
+    clients: Clients,
+    batcher: Batcher,
+    waiting: Vec<(Value, UnboundedSender<String>)>,
+    // Reads handed to our leader, with the key each one is for
+    reads: HashMap<ReadId, (String, UnboundedSender<String>)>,
+    next_read: ReadId,
+    // Lines we handed on to another peer's proposer, by the number the
+    // answer comes back with, and the ones handed on to us: who from, in
+    // which stage, its number there and where our answer turns up
+    handed_on: HashMap<u64, UnboundedSender<String>>,
+    next_handed_on: u64,
+    serving: Vec<(PeerId, PaxosStage, u64, UnboundedReceiver<String>)>,
 }
 
 impl Data {
-    pub fn new(peer_list: PeerList, nexus: Nexus) -> Self {
-        let role = peer_list.paxos_role();
-        Self {
+    pub fn new(
+        peer_list: PeerList,
+        nexus: Nexus,
+        clients: Clients,
+        batcher: Batcher,
+        settings: Settings,
+        max_value_size: usize,
+    ) -> io::Result<Self> {
+        let instances = peer_list
+            .roles()
+            .into_iter()
+            .map(|role| build(&peer_list, role, &settings))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            schedule: Schedule::new(&peer_list),
+            opened: HashSet::new(),
+            next_decision_query: None,
             peer_list,
             nexus,
-            role,
+            instances,
+            settings,
             log: VecDeque::new(),
-        }
+            loopback: VecDeque::new(),
+            max_value_size,
+            clients,
+            batcher,
+            waiting: Vec::new(),
+            reads: HashMap::new(),
+            next_read: 0,
+            handed_on: HashMap::new(),
+            next_handed_on: 0,
+            serving: Vec::new(),
+        })
     }
 
-    pub async fn send_msg(&mut self, msg: Message, to: PeerId) -> io::Result<()> {
+    /// Sends `msg` from instance `from` to a peer. Anything addressed to us
+    /// goes to our other instances instead of over the network
+    pub async fn send_msg(&mut self, from: Role, msg: Message, to: PeerId) -> io::Result<()> {
+        let id = self.peer_list.id();
+        if to == id {
+            self.loopback.push_back((from, msg));
+            return Ok(());
+        }
         self.nexus
-            .send_letter((self.peer_list.id(), msg, to).into())
+            .send_letter((id, from.stage(), msg, to).into())
             .await?;
         Ok(())
     }
 
-    /// Returns true if we are the proposer at the current stage of the system
+    /// Returns true if one of our proposers is free to take a value
     pub fn can_propose(&self) -> bool {
-        if let PaxosRole::Prop(ref p) = self.role {
-            !p.has_begun()
-        } else {
-            false
-        }
+        self.instances.iter().any(|i| self.is_free(i))
     }
 
-    /// Broadcasts a prepare message to all acceptors
+    /// True for a proposer whose stage has started and that can take a value
+    fn is_free(&self, instance: &Instance) -> bool {
+        self.schedule.has_started(instance.stage()) && instance.engine.can_propose()
+    }
+
+    /// Hands a value to our first free proposer and sends whatever it starts
+    /// with right away
     pub async fn propose(&mut self, v: Value) -> io::Result<()> {
-        let mut to_send = None;
-        if let PaxosRole::Prop(ref mut p) = self.role {
-            let msg = p.propose(v);
-            msg.paxos_print(self.peer_list.id(), true, &p.current_prop());
-            to_send = Some((msg, p.stage));
+        let Some(index) = self.instances.iter().position(|i| self.is_free(i)) else {
+            return self.check_size(&v);
+        };
+        let from = self.instances[index].role;
+        for (msg, to_peers) in self.open(index, v)? {
+            for id in to_peers {
+                self.send_msg(from, msg.clone(), id).await?;
+            }
         }
+        Ok(())
+    }
 
-        if let Some((msg, stage)) = to_send {
-            for id in self.peer_list.acceptors(stage) {
-                self.send_msg(msg.clone(), id).await?;
-            }
+    /// Hands a value to the proposer at `index` after the checks every value
+    /// goes through, whether it came from `-v`, a client or a seed
+    fn open(&mut self, index: usize, v: Value) -> io::Result<Outbox> {
+        self.check_size(&v)?;
+        let role = self.instances[index].role;
+        let Some(v) = self.refuse_reconfigs(role, v) else {
+            return Ok(Vec::new());
+        };
+        self.opened.insert(role);
+        Ok(self.instances[index].engine.propose(v, &self.peer_list))
+    }
+
+    fn check_size(&self, v: &Value) -> io::Result<()> {
+        if v.size() > self.max_value_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Value is {} bytes, the limit is {}",
+                    v.size(),
+                    self.max_value_size
+                ),
+            ));
         }
         Ok(())
     }
 
-    // checks the mailbox and does according data trickery
-    pub fn tick(&mut self) {
-        let Some(letter) = self.nexus.check_mailbox() else {
+    /// Takes out any reconfig `proposer` can't get decided as its stage's
+    /// first value, the only one that reconfigures anything, and tells the
+    /// clients that sent them. Hands back whatever is left to propose
+    fn refuse_reconfigs(&mut self, proposer: Role, v: Value) -> Option<Value> {
+        if v.reconfig().is_none() {
+            return Some(v);
+        }
+        let id = self.peer_list.id();
+        let stage = proposer.stage();
+        let error = match self.schedule.after(stage) {
+            None => format!("Stage {stage} has no stage after it to reconfigure"),
+            Some(_) if self.opened.contains(&proposer) || self.schedule.is_decided(stage) => {
+                format!("Stage {stage} already took its first value, only that one reconfigures")
+            }
+            Some(_) => return Some(v),
+        };
+
+        let (rest, reconfigs) = v.split_reconfigs();
+        for value in reconfigs {
+            eprintln!(
+                "{{\"peer_id\": {id}, \"action\": \"reconfig_refused\", \"in_stage\": {stage}, \"value\": {}, \"error\": \"{error}\"}}",
+                value.json()
+            );
+            if let Some(index) = self.waiting.iter().position(|(w, _)| w == value) {
+                let (_, reply) = self.waiting.swap_remove(index);
+                let _ = reply.send(format!(
+                    "{{\"error\": {}}}",
+                    Value::Text(error.clone()).json()
+                ));
+            }
+        }
+        rest
+    }
+
+    /// True if values get batched up before they are proposed, instead of
+    /// each going out on its own
+    pub fn is_batching(&self) -> bool {
+        self.batcher.is_batching()
+    }
+
+    /// Lines up one of our own values to be proposed with the clients' ones
+    pub fn queue(&mut self, v: Value) {
+        self.batcher.push(v, None);
+    }
+
+    /// Hands the oldest values sent to us to our first free proposer, as a
+    /// batch once enough of them have piled up or waited long enough. Clients
+    /// hear back once their value gets chosen
+    pub async fn propose_request(&mut self) -> io::Result<()> {
+        let Some((value, replies)) = self.batcher.take() else {
+            return Ok(());
+        };
+        self.waiting.extend(replies);
+        self.propose(value).await
+    }
+
+    /// Takes one request from an outside client
+    pub fn serve_clients(&mut self) {
+        let Some(Request { line, reply }) = self.clients.check_requests() else {
+            return;
+        };
+        self.serve(line, reply, false);
+    }
+
+    /// Answers a client's line, or queues its value for one of our proposers.
+    /// Anything we can't propose ourselves gets handed on to the proposer of
+    /// the running stage, unless it was handed on to us already
+    fn serve(&mut self, line: String, reply: UnboundedSender<String>, handed_on: bool) {
+        if line.trim().is_empty() {
+            return;
+        }
+        if let Some(key) = line.strip_prefix("read ") {
+            self.read(key.trim(), reply, handed_on);
             return;
+        }
+        let value = match Value::from_line(&line) {
+            Ok(value) if value.size() > self.max_value_size => {
+                let error = format!(
+                    "Value is {} bytes, the limit is {}",
+                    value.size(),
+                    self.max_value_size
+                );
+                let _ = reply.send(format!("{{\"error\": {}}}", Value::Text(error).json()));
+                return;
+            }
+            Ok(value) => value,
+            Err(e) => {
+                let _ = reply.send(format!("{{\"error\": {}}}", Value::Text(e).json()));
+                return;
+            }
         };
 
+        if let Value::Session(client, seq, _) = value {
+            match self.last_request(client) {
+                Some((stage, (last, slot, result))) if last == seq => {
+                    let _ = reply.send(answer(stage, slot, &value, result));
+                    return;
+                }
+                Some((_, (last, _, _))) if last > seq => {
+                    let _ = reply.send(format!(
+                        "{{\"error\": \"Request {seq} is older than the client's last one ({last})\"}}"
+                    ));
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        let proposes = self
+            .instances
+            .iter()
+            .any(|i| matches!(i.role, Role::Proposer(_)) && self.schedule.has_started(i.stage()));
+        if proposes {
+            self.batcher.push(value, Some(reply));
+            return;
+        }
+        self.hand_on(line, reply, handed_on);
+    }
+
+    /// Sends a line we can't serve to the first proposer of the running
+    /// stage, and keeps `reply` for the answer it sends back. A line that was
+    /// handed on to us already gets a redirect instead, so two peers that
+    /// disagree on the running stage don't pass it back and forth
+    fn hand_on(&mut self, line: String, reply: UnboundedSender<String>, handed_on: bool) {
         let id = self.peer_list.id();
-        let recmsg = letter.message();
-        match (recmsg, &mut self.role) {
-            (Message::Prepare(prop), PaxosRole::Acc(ref mut acc)) => {
-                recmsg.paxos_print(id, false, prop);
+        let Some(stage) = self.schedule.current() else {
+            self.redirect(&reply);
+            return;
+        };
+        match self.peer_list.coordinator(stage) {
+            Some(to) if to != id && !handed_on => {
+                let number = self.next_handed_on;
+                self.next_handed_on += 1;
+                self.handed_on.insert(number, reply);
+                // the role only picks the stage the letter goes out under
+                self.log.push_back((
+                    Role::Proposer(stage),
+                    Message::ClientLine(number, line),
+                    vec![to],
+                ));
+            }
+            _ => self.redirect(&reply),
+        }
+    }
+
+    /// Points a client at the proposer of the running stage
+    fn redirect(&self, reply: &UnboundedSender<String>) {
+        let leader = self
+            .schedule
+            .current()
+            .and_then(|stage| self.peer_list.coordinator(stage))
+            .and_then(|id| self.peer_list.name(id));
+        let _ = reply.send(match leader {
+            Some(name) => format!("{{\"redirect\": {}}}", Value::Text(name.into()).json()),
+            None => "{\"error\": \"No proposer to take values\"}".to_string(),
+        });
+    }
 
-                let msg = acc.prepare(prop, id);
-                self.log.push_back((msg, vec![letter.from()]));
+    /// Hands a client's read of `key` to our proposer in the running stage,
+    /// which serves it without deciding anything. Peers without one hand it on
+    fn read(&mut self, key: &str, reply: UnboundedSender<String>, handed_on: bool) {
+        let stage = self.schedule.current();
+        let Some(instance) = self
+            .instances
+            .iter_mut()
+            .find(|i| Some(i.role) == stage.map(Role::Proposer))
+        else {
+            self.hand_on(format!("read {key}"), reply, handed_on);
+            return;
+        };
+        let id = self.next_read;
+        match instance.engine.read(id, key, &self.peer_list) {
+            Ok(out) => {
+                let role = instance.role;
+                self.log
+                    .extend(out.into_iter().map(|(msg, to)| (role, msg, to)));
+                self.reads.insert(id, (key.to_string(), reply));
+                self.next_read += 1;
             }
+            Err(e) => {
+                let _ = reply.send(format!("{{\"error\": {}}}", Value::Text(e).json()));
+            }
+        }
+    }
 
-            (Message::PrepareAck(response), PaxosRole::Prop(ref mut prop)) => {
-                recmsg.paxos_print(id, false, &prop.current_prop());
+    /// Answers every read an instance of ours is done with
+    pub fn answer_reads(&mut self) {
+        for instance in &mut self.instances {
+            for (id, result) in instance.engine.answered_reads() {
+                let Some((key, reply)) = self.reads.remove(&id) else {
+                    continue;
+                };
+                let _ = reply.send(match result {
+                    Ok(value) => format!(
+                        "{{\"read\": {}, \"value\": {}}}",
+                        Value::Text(key).json(),
+                        value.map_or_else(|| "null".to_string(), |v| Value::Text(v).json())
+                    ),
+                    Err(e) => format!("{{\"error\": {}}}", Value::Text(e).json()),
+                });
+            }
+        }
+    }
 
-                if let Some(msg) = prop.acknowledge_prep(letter.from(), response.clone(), id) {
-                    self.log
-                        .push_back((msg, self.peer_list.acceptors(prop.stage)));
+    /// Tells each waiting client whose value got applied what came of it, off
+    /// our proposers' own copies of the decided log
+    pub fn answer_clients(&mut self) {
+        for index in 0..self.instances.len() {
+            let stage = self.instances[index].stage();
+            for (slot, value, result) in self.instances[index].engine.applied() {
+                if let Some(index) = self.waiting.iter().position(|(v, _)| *v == value) {
+                    let (_, reply) = self.waiting.swap_remove(index);
+                    let _ = reply.send(answer(stage, slot, &value, result));
                 }
             }
+        }
+    }
 
-            (Message::Accept(prop), PaxosRole::Acc(ref mut acceptor)) => {
-                recmsg.paxos_print(id, false, prop);
-                let msg = acceptor.accept(prop, id);
-                self.log.push_back((msg, vec![letter.from()]));
+    /// Sends back every answer we owe a peer that handed a client's line on
+    /// to us
+    pub fn answer_peers(&mut self) {
+        let log = &mut self.log;
+        self.serving.retain_mut(|(from, stage, number, answers)| {
+            match answers.try_recv() {
+                Ok(answer) => log.push_back((
+                    Role::Proposer(*stage),
+                    Message::ClientAnswer(*number, answer),
+                    vec![*from],
+                )),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {}
             }
+            false
+        });
+    }
 
-            (Message::AcceptAck { min_proposal }, PaxosRole::Prop(ref mut proposer)) => {
-                recmsg.paxos_print(id, false, &proposer.current_prop());
-                if let Some(msg) = proposer.acknowledge_accept(letter.from(), *min_proposal, id) {
-                    self.log.push_back((
-                        msg,
-                        self.peer_list.ids_and_names().map(|(id, _)| id).collect(),
-                    ));
-                }
+    /// Offers a message to every instance of ours in `stage` except the one
+    /// that sent it. Each one ignores whatever isn't meant for its role
+    fn deliver(&mut self, from: PeerId, stage: PaxosStage, msg: &Message, skip: Option<Role>) {
+        if let Message::DecisionQuery = msg {
+            if let Some(value) = self.schedule.decision(stage) {
+                let prop = Proposal {
+                    num: Ballot::default(),
+                    value: value.clone(),
+                };
+                let answer = Message::Chosen(0, prop);
+                // letters only carry the stage, so any role of it will do
+                self.log
+                    .push_back((Role::Learner(stage), answer, vec![from]));
+            }
+            return;
+        }
+        if let Message::ClientLine(number, line) = msg {
+            let (reply, answers) = unbounded_channel();
+            self.serving.push((from, stage, *number, answers));
+            self.serve(line.clone(), reply, true);
+            return;
+        }
+        if let Message::ClientAnswer(number, answer) = msg {
+            if let Some(reply) = self.handed_on.remove(number) {
+                let _ = reply.send(answer.clone());
+            }
+            return;
+        }
+        if let Message::Chosen(slot, prop) = msg {
+            self.observe(stage, *slot, &prop.value);
+        }
+        for instance in &mut self.instances {
+            if instance.stage() != stage || skip == Some(instance.role) {
+                continue;
+            }
+            let out = instance.engine.on_message(from, msg, &self.peer_list);
+            let role = instance.role;
+            self.log
+                .extend(out.into_iter().map(|(msg, to)| (role, msg, to)));
+        }
+    }
+
+    /// Hands a value chosen in `stage` to the schedule, and reconfigures the
+    /// next stage if it was the stage's decision and asks for that
+    fn observe(&mut self, stage: PaxosStage, slot: Slot, value: &Value) {
+        if self.schedule.observe(stage, slot, value) {
+            if let Some(members) = value.reconfig() {
+                self.reconfigure(stage, members);
+            }
+        } else if slot != 0 && value.reconfig().is_some() {
+            let id = self.peer_list.id();
+            eprintln!("{{\"peer_id\": {id}, \"action\": \"reconfig_refused\", \"in_stage\": {stage}, \"slot\": {slot}, \"error\": \"Only a stage's first slot reconfigures\"}}");
+        }
+    }
+
+    /// Asks every other peer for the running stage's decision now and then
+    /// until we have it. A learner of ours in that stage catches up on its
+    /// own, so then we leave it to that
+    fn query_decision(&mut self) {
+        let Some(stage) = self.schedule.current() else {
+            return;
+        };
+        if self.schedule.is_decided(stage)
+            || self
+                .instances
+                .iter()
+                .any(|i| i.role == Role::Learner(stage))
+        {
+            return;
+        }
+        let now = Instant::now();
+        match self.next_decision_query {
+            Some(at) if now >= at => {}
+            Some(_) => return,
+            None => {
+                // give the Chosen broadcast a chance first
+                self.next_decision_query = Some(now + DECISION_QUERY_INTERVAL);
+                return;
+            }
+        }
+        self.next_decision_query = Some(now + DECISION_QUERY_INTERVAL);
+        let id = self.peer_list.id();
+        let others = self
+            .peer_list
+            .everyone()
+            .into_iter()
+            .filter(|peer| *peer != id)
+            .collect();
+        self.log
+            .push_back((Role::Learner(stage), Message::DecisionQuery, others));
+    }
+
+    /// The client's last request our proposer in the running stage has
+    /// applied, as the stage's replicated session table has it
+    fn last_request(&self, client: ClientId) -> Option<(PaxosStage, LastRequest)> {
+        let stage = self.schedule.current()?;
+        self.instances
+            .iter()
+            .filter(|i| i.stage() == stage)
+            .find_map(|i| Some((stage, i.engine.last_request(client)?)))
+    }
+
+    /// A stage decided on new acceptors for the stage after it. Every peer
+    /// sees the decision before it starts that stage, so they all switch over
+    /// at the same point. We connect to anyone new, hang up on anyone left
+    /// without a role, and gain or drop our own acceptor for that stage. A
+    /// stage that has already started keeps the acceptors it started with
+    fn reconfigure(&mut self, decided_in: PaxosStage, members: &[(String, Weight)]) {
+        let id = self.peer_list.id();
+        let Some(stage) = self.schedule.after(decided_in) else {
+            return;
+        };
+        if self.schedule.has_started(stage) {
+            eprintln!("{{\"peer_id\": {id}, \"action\": \"reconfig_refused\", \"stage\": {stage}, \"error\": \"Stage {stage} has already started\"}}");
+            return;
+        }
+        let (joined, retired) = match self.peer_list.reconfigure(stage, members) {
+            Ok(changes) => changes,
+            Err(e) => {
+                eprintln!("{{\"peer_id\": {id}, \"action\": \"reconfig_refused\", \"stage\": {stage}, \"error\": \"{e}\"}}");
+                return;
+            }
+        };
+        for (peer, hostname) in joined {
+            self.nexus.connect(peer, &hostname);
+        }
+        for peer in retired {
+            self.nexus.disconnect(peer);
+        }
+
+        let role = Role::Acceptor(stage);
+        let holds = self.peer_list.roles().contains(&role);
+        let has = self.instances.iter().any(|i| i.role == role);
+        if holds && !has {
+            match build(&self.peer_list, role, &self.settings) {
+                Ok(instance) => self.instances.push(instance),
+                Err(e) => eprintln!("{{\"peer_id\": {id}, \"action\": \"reconfig_failed\", \"stage\": {stage}, \"error\": \"{e}\"}}"),
+            }
+        } else if !holds && has {
+            self.instances.retain(|i| i.role != role);
+        }
+        let quorums = self.peer_list.quorums(stage);
+        for instance in &mut self.instances {
+            if instance.stage() == stage {
+                instance.engine.reconfigure(quorums.clone());
+            }
+        }
+        eprintln!(
+            "{{\"peer_id\": {id}, \"action\": \"reconfigured\", \"stage\": {stage}, \"acceptors\": {:?}}}",
+            self.peer_list.acceptors(stage)
+        );
+    }
+
+    // checks the mailbox and does according data trickery
+    pub fn tick(&mut self) {
+        if let Some((origin, msg)) = self.loopback.pop_front() {
+            self.deliver(self.peer_list.id(), origin.stage(), &msg, Some(origin));
+            return;
+        }
+
+        let Some(letter) = self.nexus.check_mailbox() else {
+            return;
+        };
+        self.deliver(letter.from(), letter.stage(), letter.message(), None);
+    }
+
+    /// Takes in any stage decision our instances learned on their own, asks
+    /// around for the running stage's if we still need it, lets every
+    /// instance act on anything that has waited too long, settles a stage past
+    /// its deadline with a no-op, and starts the next stage once the current
+    /// one is decided
+    pub fn check_timers(&mut self) {
+        let id = self.peer_list.id();
+        let learned: Vec<(PaxosStage, Value)> = self
+            .instances
+            .iter()
+            .filter(|i| !self.schedule.is_decided(i.stage()))
+            .filter_map(|i| Some((i.stage(), i.engine.decision()?.clone())))
+            .collect();
+        for (stage, value) in learned {
+            self.observe(stage, 0, &value);
+        }
+        self.query_decision();
+
+        while let Some((stage, seed)) = self.schedule.advance(id) {
+            if let Some(seed) = seed {
+                self.open_stage(stage, seed, |_| true);
+            }
+        }
+        if let Some(stage) = self.schedule.overdue() {
+            let opened = self.opened.clone();
+            if self.open_stage(stage, Value::Noop, |role| !opened.contains(&role)) > 0 {
+                eprintln!(
+                    "{{\"peer_id\": {id}, \"action\": \"settle_overdue\", \"stage\": {stage}}}"
+                );
             }
+        }
+        self.schedule.report(id);
 
-            (Message::Chosen(prop), role) => {
-                recmsg.paxos_print(id, false, prop);
-                role.accept_choice(prop);
+        for instance in &mut self.instances {
+            let out = instance.engine.on_timer(&self.peer_list);
+            let role = instance.role;
+            self.log
+                .extend(out.into_iter().map(|(msg, to)| (role, msg, to)));
+        }
+    }
+
+    /// Has every free proposer of ours in `stage` that `wanted` picks propose
+    /// `v`, queueing what they send, and says how many did. A value the checks
+    /// turn down is logged, since nobody is waiting on it
+    fn open_stage(&mut self, stage: PaxosStage, v: Value, wanted: impl Fn(Role) -> bool) -> usize {
+        let id = self.peer_list.id();
+        let mut opened = 0;
+        for index in 0..self.instances.len() {
+            let instance = &self.instances[index];
+            let role = instance.role;
+            if instance.stage() != stage || !instance.engine.can_propose() || !wanted(role) {
+                continue;
+            }
+            match self.open(index, v.clone()) {
+                Ok(out) => {
+                    opened += 1;
+                    self.log
+                        .extend(out.into_iter().map(|(msg, to)| (role, msg, to)));
+                }
+                Err(e) => eprintln!(
+                    "{{\"peer_id\": {id}, \"action\": \"seed_refused\", \"stage\": {stage}, \"error\": {}}}",
+                    Value::Text(e.to_string()).json()
+                ),
             }
-            _ => unreachable!("These messages should only be sent by their accompanying roles"),
         }
+        opened
     }
 
     pub async fn flush_log(&mut self) -> io::Result<()> {
-        let Some((msg, to_peers)) = self.log.pop_front() else {
+        for instance in &mut self.instances {
+            instance.engine.sync()?;
+        }
+
+        let Some((from, msg, to_peers)) = self.log.pop_front() else {
             return Ok(());
         };
 
         for id in to_peers {
-            self.send_msg(msg.clone(), id).await?;
+            self.send_msg(from, msg.clone(), id).await?;
         }
 
         Ok(())
     }
 }
+
+/// Builds the engine instance for one of our roles under the protocol picked with `-e`
+/// What a client whose value got decided in `slot` and returned `result`
+/// hears back
+fn answer(stage: PaxosStage, slot: Slot, value: &Value, result: Option<String>) -> String {
+    format!(
+        "{{\"stage\": {stage}, \"slot\": {slot}, \"value\": {}, \"result\": {}}}",
+        value.json(),
+        result.map_or_else(|| "null".to_string(), |v| Value::Text(v).json())
+    )
+}
+
+fn build(peer_list: &PeerList, role: Role, settings: &Settings) -> io::Result<Instance> {
+    let data_dir = settings.data_dir.as_deref();
+    let engine: Box<dyn ConsensusEngine> = match settings.engine {
+        Engine::Paxos => {
+            Box::new(peer_list.paxos_role(role, data_dir, settings.window, settings.leases)?)
+        }
+        Engine::Raft => Box::new(peer_list.raft_role(role, data_dir)?),
+    };
+    Ok(Instance { role, engine })
+}
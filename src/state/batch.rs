@@ -0,0 +1,130 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::paxos::Value;
+
+/// Where to tell an outside client its value was chosen
+pub type Reply = UnboundedSender<String>;
+
+/// Values waiting on one of our proposers. With batching on they go out
+/// together as one `Value::Batch`, once there are `max_size` bytes of them or
+/// the oldest has waited `max_wait`. Otherwise each one gets its own proposal
+pub struct Batcher {
+    // Each value waiting, who to answer, and when it came in
+    queued: VecDeque<(Value, Option<Reply>, Instant)>,
+    max_size: Option<usize>,
+    max_wait: Duration,
+}
+impl Batcher {
+    pub fn new(max_size: Option<usize>, max_wait: Duration) -> Self {
+        Self {
+            queued: VecDeque::new(),
+            max_size,
+            max_wait,
+        }
+    }
+
+    pub fn is_batching(&self) -> bool {
+        self.max_size.is_some()
+    }
+
+    pub fn push(&mut self, value: Value, reply: Option<Reply>) {
+        self.queued.push_back((value, reply, Instant::now()));
+    }
+
+    /// The next value to propose, and the clients waiting on what is in it.
+    /// A batch takes queued values in order for as long as they fit, and
+    /// always at least one
+    pub fn take(&mut self) -> Option<(Value, Vec<(Value, Reply)>)> {
+        let Some(max_size) = self.max_size else {
+            let (value, reply, _) = self.queued.pop_front()?;
+            return Some((
+                value.clone(),
+                reply.map(|r| (value, r)).into_iter().collect(),
+            ));
+        };
+        let queued_size: usize = self.queued.iter().map(|(v, ..)| v.size()).sum();
+        let waited = self
+            .queued
+            .front()
+            .is_some_and(|(.., at)| at.elapsed() >= self.max_wait);
+        if self.queued.is_empty() || (queued_size < max_size && !waited) {
+            return None;
+        }
+
+        let mut values = Vec::new();
+        let mut replies = Vec::new();
+        let mut size = 0;
+        while let Some((value, ..)) = self.queued.front() {
+            if !values.is_empty() && size + value.size() > max_size {
+                break;
+            }
+            let (value, reply, _) = self.queued.pop_front()?;
+            size += value.size();
+            if let Some(reply) = reply {
+                replies.push((value.clone(), reply));
+            }
+            values.push(value);
+        }
+
+        let value = match values.len() {
+            1 => values.pop()?,
+            _ => Value::Batch(values),
+        };
+        Some((value, replies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    #[test]
+    fn take_without_batching_hands_out_one_value_at_a_time() {
+        let mut batcher = Batcher::new(None, Duration::from_secs(60));
+        batcher.push(text("a"), None);
+        batcher.push(text("b"), None);
+        assert_eq!(batcher.take().map(|(v, _)| v), Some(text("a")));
+        assert_eq!(batcher.take().map(|(v, _)| v), Some(text("b")));
+        assert!(batcher.take().is_none());
+    }
+
+    #[test]
+    fn take_batches_once_full_or_the_front_has_waited() {
+        let wait = Duration::from_millis(50);
+        let mut batcher = Batcher::new(Some(4), wait);
+        batcher.push(text("a"), None);
+        assert!(batcher.take().is_none());
+        batcher.push(text("bcd"), None);
+        assert_eq!(
+            batcher.take().map(|(v, _)| v),
+            Some(Value::Batch(vec![text("a"), text("bcd")]))
+        );
+
+        batcher.push(text("e"), None);
+        sleep(wait);
+        assert_eq!(batcher.take().map(|(v, _)| v), Some(text("e")));
+    }
+
+    #[test]
+    fn take_keeps_the_wait_of_values_left_behind() {
+        let wait = Duration::from_millis(50);
+        let mut batcher = Batcher::new(Some(4), wait);
+        batcher.push(text("aaa"), None);
+        batcher.push(text("bbb"), None);
+        sleep(wait);
+        // only the first fits, and the second has waited just as long
+        assert_eq!(batcher.take().map(|(v, _)| v), Some(text("aaa")));
+        assert_eq!(batcher.take().map(|(v, _)| v), Some(text("bbb")));
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+
+use super::{
+    kv::{ClientId, LastRequest},
+    paxos::{Quorums, Slot, Value},
+};
+use crate::{
+    messaging::{dist_types::PeerId, Message},
+    setup::hostsfile::PeerList,
+};
+
+/// Messages an engine wants sent, and who each one is going to
+pub type Outbox = Vec<(Message, Vec<PeerId>)>;
+
+/// Which client read an answer belongs to
+pub type ReadId = u64;
+/// A key's value as read off the replicated store, or why it couldn't be
+pub type ReadResult = Result<Option<String>, String>;
+/// A value applied off the decided log: the slot it was decided in, the
+/// value itself (a batch's come one at a time) and what applying it returned
+pub type Outcome = (Slot, Value, Option<String>);
+
+/// A consensus protocol that runs on top of the `Nexus` and `PeerList`.
+/// `Data` only ever moves messages in and out of it, so a protocol never has
+/// to touch networking and the runtime never has to know which one it is
+pub trait ConsensusEngine {
+    /// True when we can take a new value to decide
+    fn can_propose(&self) -> bool;
+
+    /// Starts deciding `v`
+    fn propose(&mut self, v: Value, peers: &PeerList) -> Outbox;
+
+    /// Handles one message `from` another peer
+    fn on_message(&mut self, from: PeerId, msg: &Message, peers: &PeerList) -> Outbox;
+
+    /// Called every loop so anything waiting on a clock can act
+    fn on_timer(&mut self, peers: &PeerList) -> Outbox;
+
+    /// Takes on new quorums after the stage's acceptors were reconfigured
+    fn reconfigure(&mut self, _quorums: Quorums) {}
+
+    /// Starts a read of `key` off the engine's own copy of the store, without
+    /// taking a slot of the log. Refused by anything that can't serve one
+    fn read(&mut self, _id: ReadId, _key: &str, _peers: &PeerList) -> Result<Outbox, String> {
+        Err("Only a Multi-Paxos leader serves reads".into())
+    }
+
+    /// Reads that are done since the last call, served or refused
+    fn answered_reads(&mut self) -> Vec<(ReadId, ReadResult)> {
+        Vec::new()
+    }
+
+    /// Values a proposer's copy of the log applied since the last call, so
+    /// the clients that sent them can hear what came of them
+    fn applied(&mut self) -> Vec<Outcome> {
+        Vec::new()
+    }
+
+    /// The last request of `client` a proposer's copy of the log applied
+    fn last_request(&self, _client: ClientId) -> Option<LastRequest> {
+        None
+    }
+
+    /// What the stage's first slot decided, if we know it by now. Covers
+    /// whatever the engine learned without a `Chosen` passing through `Data`,
+    /// like a learner catching up off acceptors or a snapshot
+    fn decision(&self) -> Option<&Value> {
+        None
+    }
+
+    /// Makes whatever the engine has to remember durable. Runs before any
+    /// message goes out
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use super::paxos::{Ballot, Chooser, Learning, PaxosStage, Proposal, Quorums, Slot, Value, Weight};
+use crate::messaging::{dist_types::PeerId, Message};
+
+/// How long fast votes, classic accepts or submitted values wait before we
+/// resend them or decide a slot needs recovering
+const FAST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Fast quorum for a stage. Any two fast quorums and a phase 1 quorum have to
+/// share an acceptor, so this is the smallest weight where 2F + q1 > total.
+/// None when q1 is more than twice the total, where there is no such weight
+pub fn fast_quorum(quorums: &Quorums) -> Option<Weight> {
+    Some((2 * quorums.total()).checked_sub(quorums.phase1)? / 2 + 1)
+}
+
+/// Picks the value phase 1 has to go with for a slot. Out of the highest ballot
+/// reported, the value with the most weight behind it is the only one that
+/// could have been chosen by a fast quorum
+fn pick(quorums: &Quorums, reports: &[(PeerId, Proposal)]) -> Option<Value> {
+    let highest = reports.iter().map(|(_, p)| p.num).max()?;
+    let mut tally: Vec<(Value, Weight)> = Vec::new();
+    for (from, prop) in reports.iter().filter(|(_, p)| p.num == highest) {
+        let weight = quorums.weigh([from]);
+        match tally.iter_mut().find(|(v, _)| *v == prop.value) {
+            Some((_, w)) => *w += weight,
+            None => tally.push((prop.value.clone(), weight)),
+        }
+    }
+    tally.into_iter().max_by_key(|(_, w)| *w).map(|(v, _)| v)
+}
+
+enum Phase {
+    // Nobody has promised us anything
+    Idle,
+    // Phase 1 for every slot from `watermark` up. `value` is only there so the
+    // prepare has something to show
+    Preparing {
+        watermark: Slot,
+        value: Value,
+        acks: HashMap<PeerId, Vec<(Slot, Proposal)>>,
+    },
+    // Acceptors can take any value into an empty slot from `from` up
+    Open {
+        from: Slot,
+    },
+}
+
+/// Coordinator of a fast stage. It runs phase 1, opens fast rounds so values can
+/// go straight to acceptors, counts the fast votes, and falls back to a classic
+/// round through itself whenever values collide in a slot
+pub struct Coordinating {
+    num: Ballot,
+    quorums: Quorums,
+    fast_quorum: Weight,
+    phase: Phase,
+    // Our own values that haven't shown up as decided yet
+    submitted: Vec<Value>,
+    decided: BTreeMap<Slot, Value>,
+    // Everything below this was compacted by the acceptors, so it is decided
+    floor: Slot,
+    // Slots we're pushing one value through with a classic accept
+    classic: BTreeMap<Slot, (Proposal, HashSet<PeerId>)>,
+    // Fast round votes for each slot, and when the first one came in
+    votes: BTreeMap<Slot, (Instant, HashMap<PeerId, Proposal>)>,
+    deadline: Option<Instant>,
+    // Decided values applied in slot order, to tell clients what came of theirs
+    pub applied: Learning,
+    pub stage: PaxosStage,
+}
+impl Coordinating {
+    pub fn new(quorums: Quorums, stage: PaxosStage, id: PeerId) -> Self {
+        Self {
+            num: Ballot {
+                round: 0,
+                proposer: id,
+            },
+            fast_quorum: quorums.fast.expect("Fast stages have a fast quorum"),
+            quorums: quorums.clone(),
+            phase: Phase::Idle,
+            submitted: Vec::new(),
+            decided: BTreeMap::new(),
+            floor: 0,
+            classic: BTreeMap::new(),
+            votes: BTreeMap::new(),
+            deadline: None,
+            applied: Learning::quiet(quorums.clone(), stage),
+            stage,
+        }
+    }
+
+    pub fn reconfigure(&mut self, quorums: Quorums) {
+        self.fast_quorum = quorums.fast.expect("Fast stages have a fast quorum");
+        self.applied.reconfigure(quorums.clone());
+        self.quorums = quorums;
+    }
+
+    /// Returns true while any of our own values is still undecided
+    pub fn has_begun(&self) -> bool {
+        !self.submitted.is_empty()
+    }
+
+    pub fn current_prop(&self) -> Proposal {
+        let value = match self.phase {
+            Phase::Preparing { ref value, .. } => value.clone(),
+            _ => self
+                .submitted
+                .first()
+                .cloned()
+                .unwrap_or(Value::Bytes(Vec::new())),
+        };
+        Proposal {
+            num: self.num,
+            value,
+        }
+    }
+
+    fn first_undecided(&self) -> Slot {
+        let mut slot = self.floor;
+        for decided in self.decided.range(self.floor..).map(|(slot, _)| slot) {
+            if *decided != slot {
+                break;
+            }
+            slot += 1;
+        }
+        slot
+    }
+
+    /// Submits one of our values. With a fast round open it goes straight to
+    /// the acceptors, otherwise it gets a round opened for it
+    pub fn propose(&mut self, v: Value, id: PeerId) -> Vec<Message> {
+        self.submitted.push(v.clone());
+        self.deadline = Some(Instant::now() + FAST_TIMEOUT);
+        match self.phase {
+            Phase::Open { .. } => vec![Message::FastPropose(v)],
+            Phase::Preparing { .. } => Vec::new(),
+            Phase::Idle => vec![self.prepare(self.first_undecided(), v, id)],
+        }
+    }
+
+    /// An acceptor had no fast round open for a client's value. The client will
+    /// resend it, we just make sure a round is open by then
+    pub fn forwarded(&mut self, v: Value, id: PeerId) -> Option<Message> {
+        matches!(self.phase, Phase::Idle).then(|| self.prepare(self.first_undecided(), v, id))
+    }
+
+    /// Starts phase 1 under a fresh ballot for every slot from `watermark` up.
+    /// This closes any fast round open on those slots
+    fn prepare(&mut self, watermark: Slot, value: Value, id: PeerId) -> Message {
+        self.num.round += 1;
+        self.classic.retain(|slot, _| *slot < watermark);
+        let prop = Proposal {
+            num: self.num,
+            value: value.clone(),
+        };
+        self.phase = Phase::Preparing {
+            watermark,
+            value,
+            acks: HashMap::new(),
+        };
+        self.deadline = Some(Instant::now() + FAST_TIMEOUT);
+
+        let msg = Message::Prepare(watermark, prop.clone());
+        msg.paxos_print(id, true, &prop);
+        msg
+    }
+
+    /// Counts a promise. Once a phase 1 quorum is in, any slot they had accepted
+    /// something in gets finished with a classic accept, and a fast round opens
+    /// on every slot above those
+    pub fn acknowledge_prep(
+        &mut self,
+        watermark: Slot,
+        from: PeerId,
+        promised: Ballot,
+        accepted: Vec<(Slot, Proposal)>,
+        id: PeerId,
+    ) -> Vec<Message> {
+        let Phase::Preparing {
+            watermark: preparing,
+            ref mut acks,
+            ..
+        } = self.phase
+        else {
+            return Vec::new();
+        };
+        if watermark != preparing || promised != self.num {
+            return Vec::new();
+        }
+        acks.insert(from, accepted);
+        if self.quorums.weigh(acks.keys()) < self.quorums.phase1 {
+            return Vec::new();
+        }
+
+        let mut reports: BTreeMap<Slot, Vec<(PeerId, Proposal)>> = BTreeMap::new();
+        for (from, accepted) in acks.iter() {
+            for (slot, prop) in accepted {
+                reports
+                    .entry(*slot)
+                    .or_default()
+                    .push((*from, prop.clone()));
+            }
+        }
+
+        let mut out = Vec::new();
+        let mut from = self.first_undecided().max(watermark);
+        for (slot, reports) in reports {
+            if self.decided.contains_key(&slot) {
+                continue;
+            }
+            let Some(value) = pick(&self.quorums, &reports) else {
+                continue;
+            };
+            let prop = Proposal {
+                num: self.num,
+                value,
+            };
+            let msg = Message::Accept(slot, prop.clone());
+            msg.paxos_print(id, true, &prop);
+            out.push(msg);
+            self.classic.insert(slot, (prop, HashSet::new()));
+            from = from.max(slot + 1);
+        }
+
+        self.votes.retain(|slot, _| *slot < watermark);
+        self.phase = Phase::Open { from };
+        out.push(Message::AnyValue(from, self.num));
+        out.extend(self.resubmit());
+        out
+    }
+
+    /// Our values that still need a slot. Ones already riding on a classic
+    /// accept would just get decided twice
+    fn resubmit(&self) -> impl Iterator<Item = Message> + '_ {
+        self.submitted
+            .iter()
+            .filter(|v| !self.classic.values().any(|(prop, _)| prop.value == **v))
+            .cloned()
+            .map(Message::FastPropose)
+    }
+
+    /// Counts a classic accept for a slot we are pushing a value through
+    pub fn acknowledge_accept(
+        &mut self,
+        slot: Slot,
+        from: PeerId,
+        accepted: Ballot,
+        id: PeerId,
+    ) -> Option<Message> {
+        if accepted != self.num {
+            return None;
+        }
+        let (_, acks) = self.classic.get_mut(&slot)?;
+        acks.insert(from);
+        if self.quorums.weigh(acks.iter()) < self.quorums.phase2 {
+            return None;
+        }
+        let (prop, _) = self.classic.remove(&slot)?;
+        Some(self.decide(slot, prop, id))
+    }
+
+    /// Counts an acceptor's fast vote. A value is chosen once a fast quorum
+    /// voted for it, and a slot goes to recovery once no value can get there
+    pub fn fast_accepted(
+        &mut self,
+        slot: Slot,
+        from: PeerId,
+        prop: Proposal,
+        id: PeerId,
+    ) -> Vec<Message> {
+        if prop.num != self.num || self.decided.contains_key(&slot) {
+            return Vec::new();
+        }
+        let (_, votes) = self
+            .votes
+            .entry(slot)
+            .or_insert_with(|| (Instant::now(), HashMap::new()));
+        votes.insert(from, prop.clone());
+
+        let for_value = self.quorums.weigh(
+            votes
+                .iter()
+                .filter(|(_, p)| p.value == prop.value)
+                .map(|(id, _)| id),
+        );
+        if for_value >= self.fast_quorum {
+            return vec![self.decide(slot, prop, id)];
+        }
+
+        // the best any value can still do is whatever it has plus everyone
+        // that hasn't voted yet
+        let voted = self.quorums.weigh(votes.keys());
+        let best = votes
+            .values()
+            .map(|p| {
+                self.quorums.weigh(
+                    votes
+                        .iter()
+                        .filter(|(_, q)| q.value == p.value)
+                        .map(|(id, _)| id),
+                )
+            })
+            .max()
+            .unwrap_or(0);
+        if best + (self.quorums.total() - voted) < self.fast_quorum {
+            return self.recover(slot, id);
+        }
+        Vec::new()
+    }
+
+    /// Values collided in `slot`, so it gets a classic round through us
+    fn recover(&mut self, slot: Slot, id: PeerId) -> Vec<Message> {
+        if let Phase::Preparing { watermark, .. } = self.phase {
+            if watermark <= slot {
+                return Vec::new();
+            }
+        }
+        let reports: Vec<(PeerId, Proposal)> = self
+            .votes
+            .get(&slot)
+            .map(|(_, votes)| votes.iter().map(|(id, p)| (*id, p.clone())).collect())
+            .unwrap_or_default();
+        let value = pick(&self.quorums, &reports).unwrap_or_else(|| self.current_prop().value);
+        vec![self.prepare(slot, value, id)]
+    }
+
+    fn decide(&mut self, slot: Slot, prop: Proposal, id: PeerId) -> Message {
+        self.settle(slot, &prop);
+        let chose_msg = Message::Chosen(slot, prop.clone());
+        chose_msg.paxos_print(id, true, &prop);
+        chose_msg
+    }
+
+    fn settle(&mut self, slot: Slot, prop: &Proposal) {
+        self.votes.remove(&slot);
+        self.classic.remove(&slot);
+        if let Some(ours) = self.submitted.iter().position(|v| *v == prop.value) {
+            self.submitted.remove(ours);
+        }
+        self.decided.insert(slot, prop.value.clone());
+        self.applied.accept_choice(slot, prop);
+    }
+
+    /// The acceptors compacted everything below `upto`, so all of it is
+    /// decided. Phase 1 below that starts over from past it
+    pub fn skip_to(&mut self, upto: Slot, id: PeerId) -> Vec<Message> {
+        if upto <= self.floor {
+            return Vec::new();
+        }
+        self.floor = upto;
+        self.applied.want(upto);
+        self.decided = self.decided.split_off(&upto);
+        self.classic = self.classic.split_off(&upto);
+        self.votes = self.votes.split_off(&upto);
+        match self.phase {
+            Phase::Preparing {
+                watermark,
+                ref value,
+                ..
+            } if watermark < upto => {
+                let value = value.clone();
+                vec![self.prepare(self.first_undecided(), value, id)]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Someone promised a higher ballot than ours, so start over above it
+    pub fn preempted(&mut self, promised: Ballot, id: PeerId) -> Option<Message> {
+        if promised <= self.num {
+            return None;
+        }
+        self.num = self.num.succeed(promised);
+        self.num.round -= 1;
+        self.phase = Phase::Idle;
+        self.classic.clear();
+        let value = self.submitted.first()?.clone();
+        Some(self.prepare(self.first_undecided(), value, id))
+    }
+
+    /// Resends whatever is stuck, and sends slots whose fast votes never
+    /// settled off to recovery
+    pub fn on_timer(&mut self, id: PeerId) -> Vec<Message> {
+        let now = Instant::now();
+        if self.deadline.is_none_or(|deadline| now < deadline) {
+            return Vec::new();
+        }
+        self.deadline = Some(now + FAST_TIMEOUT);
+
+        match self.phase {
+            Phase::Idle => match self.submitted.first() {
+                Some(v) => vec![self.prepare(self.first_undecided(), v.clone(), id)],
+                None => Vec::new(),
+            },
+            Phase::Preparing { watermark, .. } => {
+                let msg = Message::Prepare(watermark, self.current_prop());
+                msg.paxos_print(id, true, &self.current_prop());
+                vec![msg]
+            }
+            Phase::Open { from } => {
+                let stalled = self
+                    .votes
+                    .iter()
+                    .find(|(_, (since, _))| now.duration_since(*since) >= FAST_TIMEOUT)
+                    .map(|(slot, _)| *slot);
+                if let Some(slot) = stalled {
+                    return self.recover(slot, id);
+                }
+                if self.classic.is_empty() && self.submitted.is_empty() {
+                    return Vec::new();
+                }
+                // an acceptor that missed the round opening can still join in
+                let mut out: Vec<Message> = self
+                    .classic
+                    .iter()
+                    .map(|(slot, (prop, _))| Message::Accept(*slot, prop.clone()))
+                    .collect();
+                out.push(Message::AnyValue(from, self.num));
+                out.extend(self.resubmit());
+                out
+            }
+        }
+    }
+}
+impl Chooser for Coordinating {
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal) {
+        self.settle(slot, prop);
+    }
+}
+
+/// Proposer of a fast stage that isn't its coordinator. It hands its values
+/// straight to the acceptors and keeps resending them until they are decided
+pub struct Submitting {
+    outstanding: Vec<Value>,
+    deadline: Option<Instant>,
+    // Decided values applied in slot order, to tell clients what came of theirs
+    pub applied: Learning,
+    pub stage: PaxosStage,
+}
+impl Submitting {
+    pub fn new(quorums: Quorums, stage: PaxosStage) -> Self {
+        Self {
+            outstanding: Vec::new(),
+            deadline: None,
+            applied: Learning::quiet(quorums, stage),
+            stage,
+        }
+    }
+
+    pub fn has_begun(&self) -> bool {
+        !self.outstanding.is_empty()
+    }
+
+    pub fn propose(&mut self, v: Value) -> Message {
+        self.outstanding.push(v.clone());
+        self.deadline = Some(Instant::now() + FAST_TIMEOUT);
+        Message::FastPropose(v)
+    }
+
+    pub fn on_timer(&mut self) -> Vec<Message> {
+        let now = Instant::now();
+        if self.deadline.is_none_or(|deadline| now < deadline) {
+            return Vec::new();
+        }
+        self.deadline = Some(now + FAST_TIMEOUT);
+        self.outstanding
+            .iter()
+            .cloned()
+            .map(Message::FastPropose)
+            .collect()
+    }
+}
+impl Chooser for Submitting {
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal) {
+        if let Some(ours) = self.outstanding.iter().position(|v| *v == prop.value) {
+            self.outstanding.remove(ours);
+        }
+        self.applied.accept_choice(slot, prop);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quorums(weights: &[Weight]) -> Quorums {
+        Quorums::majority((1..).zip(weights.iter().copied()).collect())
+    }
+
+    fn report(from: PeerId, round: u64, value: &str) -> (PeerId, Proposal) {
+        let num = Ballot { round, proposer: 1 };
+        (
+            from,
+            Proposal {
+                num,
+                value: Value::Text(value.into()),
+            },
+        )
+    }
+
+    #[test]
+    fn fast_quorum_is_the_smallest_that_overlaps() {
+        assert_eq!(fast_quorum(&quorums(&[1, 1, 1])), Some(3));
+        assert_eq!(fast_quorum(&quorums(&[1, 1, 1, 1, 1])), Some(4));
+        assert_eq!(fast_quorum(&quorums(&[3, 1, 1])), Some(4));
+        assert_eq!(fast_quorum(&quorums(&[])), None);
+        let mut q = quorums(&[1, 1, 1]);
+        q.phase1 = 7;
+        assert_eq!(fast_quorum(&q), None);
+
+        for total in 1..=9 {
+            for phase1 in total / 2 + 1..=total {
+                let mut q = quorums(&vec![1; total as usize]);
+                q.phase1 = phase1;
+                let fast = fast_quorum(&q).expect("q1 is at most the total");
+                assert!(
+                    2 * fast + phase1 > 2 * total,
+                    "{fast} too small for q1={phase1} of {total}"
+                );
+                assert!(
+                    2 * (fast - 1) + phase1 <= 2 * total,
+                    "{fast} too big for q1={phase1} of {total}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pick_goes_with_the_heaviest_value_at_the_highest_ballot() {
+        let q = quorums(&[1, 1, 1, 1, 1]);
+        assert_eq!(pick(&q, &[]), None);
+
+        let reports = [
+            report(1, 2, "a"),
+            report(2, 2, "b"),
+            report(3, 2, "b"),
+            report(4, 1, "a"),
+        ];
+        assert_eq!(pick(&q, &reports), Some(Value::Text("b".into())));
+
+        // votes from an older ballot don't count, however many there are
+        let reports = [
+            report(1, 1, "a"),
+            report(2, 1, "a"),
+            report(3, 1, "a"),
+            report(4, 3, "c"),
+        ];
+        assert_eq!(pick(&q, &reports), Some(Value::Text("c".into())));
+
+        // one heavy acceptor outweighs two light ones
+        let q = quorums(&[3, 1, 1]);
+        let reports = [report(1, 2, "a"), report(2, 2, "b"), report(3, 2, "b")];
+        assert_eq!(pick(&q, &reports), Some(Value::Text("a".into())));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{paxos::Slot, value::Value};
+
+/// Who sent a request from outside the cluster, and which of their requests it is
+pub type ClientId = u64;
+pub type Seq = u64;
+/// A client's last applied request: its sequence number, the slot it was
+/// decided in and what it returned
+pub type LastRequest = (Seq, Slot, Option<String>);
+
+/// An operation on the replicated key-value store. Commands get decided like
+/// any other value, and every learner applies them in slot order, so all their
+/// stores go through the same changes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Put(String, String),
+    Get(String),
+    Delete(String),
+    // Sets the key to the last value only if it currently holds the middle one
+    Cas(String, String, String),
+}
+impl Command {
+    /// Parses `put KEY VALUE`, `get KEY`, `delete KEY` or `cas KEY OLD NEW`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut words = text.split_whitespace();
+        let op = words.next().ok_or("Empty key-value command")?;
+        let args: Vec<String> = words.map(String::from).collect();
+        let command = match (op, args.as_slice()) {
+            ("put", [key, value]) => Command::Put(key.clone(), value.clone()),
+            ("get", [key]) => Command::Get(key.clone()),
+            ("delete", [key]) => Command::Delete(key.clone()),
+            ("cas", [key, old, new]) => Command::Cas(key.clone(), old.clone(), new.clone()),
+            ("put" | "get" | "delete" | "cas", _) => {
+                return Err(format!("Wrong number of arguments for {op}"))
+            }
+            _ => return Err(format!("Unknown key-value command {op}")),
+        };
+        Ok(command)
+    }
+
+    /// Size of the keys and values in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Put(key, value) => key.len() + value.len(),
+            Self::Get(key) | Self::Delete(key) => key.len(),
+            Self::Cas(key, old, new) => key.len() + old.len() + new.len(),
+        }
+    }
+
+    /// The command written the way it is parsed, without the `kv:` prefix
+    pub fn text(&self) -> String {
+        match self {
+            Self::Put(key, value) => format!("put {key} {value}"),
+            Self::Get(key) => format!("get {key}"),
+            Self::Delete(key) => format!("delete {key}"),
+            Self::Cas(key, old, new) => format!("cas {key} {old} {new}"),
+        }
+    }
+}
+
+/// A learner's copy of the key-value map
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Store {
+    entries: BTreeMap<String, String>,
+}
+impl Store {
+    /// Applies a command and hands back its result. Puts and deletes give the
+    /// key's previous value, gets its current one, and cas whether it swapped
+    pub fn apply(&mut self, command: &Command) -> Option<String> {
+        match command {
+            Command::Put(key, value) => self.entries.insert(key.clone(), value.clone()),
+            Command::Get(key) => self.entries.get(key).cloned(),
+            Command::Delete(key) => self.entries.remove(key),
+            Command::Cas(key, old, new) => {
+                let swapped = self.entries.get(key) == Some(old);
+                if swapped {
+                    self.entries.insert(key.clone(), new.clone());
+                }
+                Some(swapped.to_string())
+            }
+        }
+    }
+
+    /// The key's current value
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.entries.get(key).cloned()
+    }
+
+    /// Renders the whole map as a JSON object
+    pub fn json(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    "{}: {}",
+                    Value::Text(key.clone()).json(),
+                    Value::Text(value.clone()).json()
+                )
+            })
+            .collect();
+        format!("{{{}}}", entries.join(", "))
+    }
+}
+
+/// The last request each client had applied, the slot it was decided in and
+/// what it returned. Every
+/// learner keeps one alongside its store and fills it in slot order, so they
+/// all agree on which decided requests were retries. Kept sorted so every
+/// peer serializes the same table to the same bytes
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Sessions {
+    last: BTreeMap<ClientId, LastRequest>,
+}
+impl Sessions {
+    /// What the request returned when it was first applied, if it already
+    /// was. Anything older than the client's last request counts as applied,
+    /// with whatever that returned long gone
+    pub fn applied(&self, client: ClientId, seq: Seq) -> Option<Option<String>> {
+        let (last, _, result) = self.last.get(&client)?;
+        match seq.cmp(last) {
+            std::cmp::Ordering::Greater => None,
+            std::cmp::Ordering::Equal => Some(result.clone()),
+            std::cmp::Ordering::Less => Some(None),
+        }
+    }
+
+    /// The client's last applied request
+    pub fn last(&self, client: ClientId) -> Option<LastRequest> {
+        self.last.get(&client).cloned()
+    }
+
+    /// Takes note of a client's request being applied
+    pub fn record(&mut self, client: ClientId, seq: Seq, slot: Slot, result: Option<String>) {
+        self.last.insert(client, (seq, slot, result));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_serialize_the_same_whatever_order_they_were_recorded_in() {
+        let mut forwards = Sessions::default();
+        let mut backwards = Sessions::default();
+        for client in 0..50 {
+            forwards.record(client, 1, client, Some(client.to_string()));
+            backwards.record(49 - client, 1, 49 - client, Some((49 - client).to_string()));
+        }
+        let bytes = |sessions: &Sessions| bincode::serialize(sessions).expect("Serializable");
+        assert_eq!(bytes(&forwards), bytes(&backwards));
+    }
+}
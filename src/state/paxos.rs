@@ -1,214 +1,1861 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::messaging::{dist_types::PeerId, Message};
+pub use super::value::Value;
+use super::{
+    engine::{ConsensusEngine, Outbox, Outcome, ReadId, ReadResult},
+    fast::{Coordinating, Submitting},
+    kv::{ClientId, LastRequest},
+    snapshot::{Applied, Received, Snapshot, Transfer},
+    wal::{Wal, WalRecord},
+};
+use crate::{
+    messaging::{dist_types::PeerId, Message},
+    setup::hostsfile::PeerList,
+};
 
-/// Passed Between proposors and acceptors. Just a monotonically increasing integer
-pub type ProposalNum = u64;
-/// Chars that represent accepted values
-pub type Value = char;
+/// Passed Between proposors and acceptors. Rounds only ever go up, and tagging
+/// them with the proposer's id means no two proposers share a ballot.
+/// Ordering compares the round first, then the id
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ballot {
+    pub round: u64,
+    pub proposer: PeerId,
+}
+impl Ballot {
+    /// The lowest ballot of ours that beats `other`
+    pub fn succeed(self, other: Ballot) -> Ballot {
+        Ballot {
+            round: self.round.max(other.round) + 1,
+            proposer: self.proposer,
+        }
+    }
+}
+impl fmt::Display for Ballot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.round, self.proposer)
+    }
+}
+/// Index of an entry in the replicated log
+pub type Slot = u64;
+
+/// How long a phase waits to hear from a quorum before resending
+const PHASE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Resends a phase gets before we give up on the round entirely
+const MAX_RETRANSMITS: u32 = 3;
+/// Backoff before re-preparing starts here, then doubles each round lost in a row
+const BASE_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(4);
+/// How often a learner asks around for slots it might have missed
+const CATCHUP_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a snapshot transfer goes without a chunk from its source before
+/// we drop it and ask around again
+const TRANSFER_STALL: Duration = Duration::from_secs(3);
+/// How long an acceptor turns down other proposers after granting a lease
+const LEASE: Duration = Duration::from_secs(2);
+/// Taken off the leader's end of a lease, so it runs out there first even
+/// with the acceptors' clocks running up to 10% fast against the leader's
+const LEASE_DRIFT: Duration = Duration::from_millis(200);
+/// How often the leader asks for its lease to be renewed
+const LEASE_RENEW: Duration = Duration::from_millis(500);
+/// How long a read waits on the leader to catch up before it gets refused
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// As per the hostsfile, this is a ket referencing
 /// which round of the testcases we are in
 pub type PaxosStage = u32;
 
+/// How many votes an acceptor's answer is worth
+pub type Weight = u64;
+
+/// How many acceptor votes each phase has to collect. Flexible paxos only needs
+/// every phase 1 quorum to overlap every phase 2 quorum, so these just have to
+/// add up to more than the total weight rather than both be majorities
+#[derive(Clone, Debug, Default)]
+pub struct Quorums {
+    pub weights: HashMap<PeerId, Weight>,
+    pub phase1: Weight,
+    pub phase2: Weight,
+    // Votes a value needs in a fast round. Only set for fast stages
+    pub fast: Option<Weight>,
+}
+impl Quorums {
+    pub fn majority(weights: HashMap<PeerId, Weight>) -> Self {
+        let total: Weight = weights.values().sum();
+        Self {
+            weights,
+            phase1: total / 2 + 1,
+            phase2: total / 2 + 1,
+            fast: None,
+        }
+    }
+
+    /// Votes across every acceptor in the stage
+    pub fn total(&self) -> Weight {
+        self.weights.values().sum()
+    }
+
+    /// Combined votes of the given acceptors. Peers that aren't one of our
+    /// acceptors count for nothing
+    pub fn weigh<'a>(&self, peers: impl IntoIterator<Item = &'a PeerId>) -> Weight {
+        peers
+            .into_iter()
+            .filter_map(|id| self.weights.get(id))
+            .sum()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Proposal {
-    pub num: ProposalNum,
+    pub num: Ballot,
     pub value: Value,
 }
 
-#[derive(Default)]
+/// A client's read waiting on the leader
+struct Read {
+    id: ReadId,
+    key: String,
+    // One past every slot that could have held a chosen value when the read
+    // came in. It waits until all of them are applied
+    index: Slot,
+    // The heartbeat round that has to show we still lead, when there are
+    // no leases
+    round: u64,
+    since: Instant,
+}
+
+/// A slot we put a value in and haven't seen chosen yet
+struct InFlight {
+    value: Value,
+    // The value we were asked to decide here, in case phase 1 makes us adopt
+    // someone else's
+    own_value: Option<Value>,
+    // Whether this slot is past phase 1 and waiting on accepts
+    accepting: bool,
+    accept_acks: HashSet<PeerId>,
+    // Acceptors that turned down the phase in flight, and what they promised instead
+    nacks: HashMap<PeerId, Ballot>,
+    // When the phase in flight stops waiting, and how often it has been resent
+    deadline: Option<Instant>,
+    retransmits: u32,
+}
+impl InFlight {
+    fn new(value: Value, own_value: Option<Value>, accepting: bool) -> Self {
+        Self {
+            value,
+            own_value,
+            accepting,
+            accept_acks: HashSet::new(),
+            nacks: HashMap::new(),
+            deadline: Some(Instant::now() + PHASE_TIMEOUT),
+            retransmits: 0,
+        }
+    }
+
+    /// Starts the clock on a phase we just sent out
+    fn arm(&mut self) {
+        self.deadline = Some(Instant::now() + PHASE_TIMEOUT);
+        self.retransmits = 0;
+    }
+
+    /// True once enough acceptors have said no that the rest can't make a quorum
+    fn quorum_lost(&self, quorums: &Quorums) -> bool {
+        let needed = if self.accepting {
+            quorums.phase2
+        } else {
+            quorums.phase1
+        };
+        quorums.total() - quorums.weigh(self.nacks.keys()) < needed
+    }
+}
+
 pub struct Proposing {
-    num: ProposalNum,
-    value: Option<Value>,
-    prep_acks: HashMap<PeerId, Option<Proposal>>,
-    broadcasted_accept: bool,
-    accept_acks: HashMap<PeerId, ProposalNum>,
-    quorum_size: usize,
-    chosen: bool,
+    num: Ballot,
+    // Most slots we keep in the accept phase at once as the stable leader
+    window: usize,
+    // Slots we put a value in and are waiting on. Only ever one while we
+    // run phase 1 for a fresh value
+    in_flight: BTreeMap<Slot, InFlight>,
+    // Values still waiting on a slot of their own
+    pending: VecDeque<Value>,
+    // Slots we know are decided, so we never start a fresh one there. Every
+    // slot below `floor` was compacted by the acceptors, so it is too
+    decided: BTreeSet<Slot>,
+    floor: Slot,
+    // Set once a quorum has promised `num` for every slot from `watermark` up.
+    // While it holds we are the stable leader and skip straight to accepts
+    leading: bool,
+    watermark: Slot,
+    // Values the promising quorum had already accepted above the watermark.
+    // These slots have to be finished before they can hold anything new
+    recovered: BTreeMap<Slot, Proposal>,
+    prep_acks: HashMap<PeerId, Vec<(Slot, Proposal)>>,
+    // Rounds lost in a row, and when the backoff after the last one is over
+    failed_rounds: u32,
+    backoff_until: Option<Instant>,
+    // Whether we ask for leases at all. A lease keeps every other proposer
+    // from getting promises, so their values wait until we stop leading
+    leases: bool,
+    // Our lease from the acceptors while leading: the renewal out for votes,
+    // when it went out and who granted it, and when the last one a quorum
+    // granted runs out
+    lease_seq: u64,
+    lease_sent: Option<Instant>,
+    lease_grants: HashSet<PeerId>,
+    lease_until: Option<Instant>,
+    // Without leases, reads wait on a heartbeat a quorum acks under our
+    // ballot instead: the round out, when it went out and who acked it, and
+    // the last rounds a quorum confirmed and that ran out of time
+    heartbeat_seq: u64,
+    heartbeat_sent: Option<Instant>,
+    heartbeat_acks: HashSet<PeerId>,
+    confirmed: u64,
+    unconfirmed: u64,
+    // Values we've seen chosen, applied in slot order so the leader can
+    // serve reads off them. It catches up on any it missed like a learner
+    applied: Learning,
+    reads: Vec<Read>,
+    quorums: Quorums,
     pub stage: PaxosStage,
 }
 impl Proposing {
-    pub fn new(quorum_size: usize, stage: PaxosStage) -> Self {
-        let num = stage as u64 - 1;
+    pub fn new(
+        quorums: Quorums,
+        stage: PaxosStage,
+        id: PeerId,
+        window: usize,
+        leases: bool,
+    ) -> Self {
         Self {
-            quorum_size,
-            broadcasted_accept: false,
-            chosen: false,
+            num: Ballot {
+                round: 0,
+                proposer: id,
+            },
+            window: window.max(1),
+            in_flight: BTreeMap::new(),
+            pending: VecDeque::new(),
+            decided: BTreeSet::new(),
+            floor: 0,
+            leading: false,
+            watermark: 0,
+            recovered: BTreeMap::new(),
+            prep_acks: HashMap::new(),
+            failed_rounds: 0,
+            backoff_until: None,
+            leases,
+            lease_seq: 0,
+            lease_sent: None,
+            lease_grants: HashSet::new(),
+            lease_until: None,
+            heartbeat_seq: 0,
+            heartbeat_sent: None,
+            heartbeat_acks: HashSet::new(),
+            confirmed: 0,
+            unconfirmed: 0,
+            applied: Learning::quiet(quorums.clone(), stage),
+            reads: Vec::new(),
+            quorums,
             stage,
-            num,
-            ..Default::default()
         }
     }
-    /// Returns true if we have proposed a value
-    pub fn has_begun(&self) -> bool {
-        self.value.is_some()
+
+    /// Returns true if a new value could go out right away. The stable
+    /// leader has room for `window` slots at once, anyone else for one
+    pub fn has_room(&self) -> bool {
+        let window = if self.leading { self.window } else { 1 };
+        self.pending.is_empty() && self.recovered.is_empty() && self.in_flight.len() < window
     }
 
-    pub fn current_prop(&self) -> Proposal {
+    /// What we are proposing in `slot`, for printing messages about it
+    pub fn current_prop(&self, slot: Slot) -> Proposal {
         Proposal {
             num: self.num,
-            value: self.value.expect("Dont mess this up"),
+            value: self
+                .in_flight
+                .get(&slot)
+                .map_or(Value::Bytes(Vec::new()), |flight| flight.value.clone()),
         }
     }
 
-    /// Sets proposal value and increments proposal number
-    pub fn propose(&mut self, v: Value) -> Message {
-        assert!(!self.has_begun());
-        self.num += 1;
-        self.value = Some(v);
+    /// Lowest slot nobody has told us is decided and we aren't already using
+    fn next_free(&self) -> Slot {
+        let mut slot = self.floor;
+        while self.decided.contains(&slot) || self.in_flight.contains_key(&slot) {
+            slot += 1;
+        }
+        slot
+    }
 
-        Message::Prepare(Proposal {
-            num: self.num,
-            value: v,
-        })
+    /// Queues a value for its own slot, starting on it right away if there
+    /// is room
+    pub fn propose(&mut self, v: Value, id: PeerId) -> Vec<Message> {
+        self.pending.push_back(v);
+        self.resume(id)
+    }
+
+    /// Moves onto the next slots. As the stable leader this sends accepts
+    /// until `window` slots are in flight, otherwise it runs phase 1 for
+    /// every slot from the next one up once nothing else is in flight
+    pub fn resume(&mut self, id: PeerId) -> Vec<Message> {
+        if self.backoff_until.is_some() {
+            return Vec::new();
+        }
+
+        if !self.leading {
+            if !self.in_flight.is_empty() {
+                return Vec::new();
+            }
+            let Some(v) = self.pending.pop_front() else {
+                return Vec::new();
+            };
+            let slot = self.next_free();
+            self.num.round += 1;
+            self.watermark = slot;
+            self.in_flight
+                .insert(slot, InFlight::new(v.clone(), Some(v), false));
+            let prop = self.current_prop(slot);
+            let msg = Message::Prepare(slot, prop.clone());
+            msg.paxos_print(id, true, &prop);
+            return vec![msg];
+        }
+
+        let mut out = Vec::new();
+        while self.in_flight.len() < self.window {
+            let mut slot = self.next_free();
+            let (value, own_value) = if let Some(prop) = self.recovered.remove(&slot) {
+                (prop.value, None)
+            } else if let Some(v) = self.pending.pop_front() {
+                (v.clone(), Some(v))
+            } else if let Some((recovered_slot, prop)) = self.recovered.pop_first() {
+                // nothing new to say, but a recovered slot still needs finishing
+                slot = recovered_slot;
+                (prop.value, None)
+            } else {
+                break;
+            };
+
+            self.in_flight
+                .insert(slot, InFlight::new(value, own_value, true));
+            let prop = self.current_prop(slot);
+            let msg = Message::Accept(slot, prop.clone());
+            msg.paxos_print(id, true, &prop);
+            out.push(msg);
+        }
+        out
+    }
+
+    /// Resends whatever phase is stuck waiting, or gives up on the round once it
+    /// has been resent enough. Also sends the prepare once a backoff is over
+    pub fn on_timer(&mut self, id: PeerId) -> Vec<Message> {
+        let now = Instant::now();
+        if let Some(until) = self.backoff_until {
+            if now < until {
+                return Vec::new();
+            }
+            self.backoff_until = None;
+            let Some((&slot, flight)) = self.in_flight.first_key_value() else {
+                return self.resume(id);
+            };
+            let prop = Proposal {
+                num: self.num,
+                value: flight.value.clone(),
+            };
+            self.watermark = slot;
+            if let Some(flight) = self.in_flight.get_mut(&slot) {
+                flight.arm();
+            }
+            let redo_prep = Message::Prepare(slot, prop.clone());
+            redo_prep.paxos_print(id, true, &prop);
+            return vec![redo_prep];
+        }
+
+        let expired: Vec<Slot> = self
+            .in_flight
+            .iter()
+            .filter(|(_, flight)| flight.deadline.is_some_and(|deadline| now >= deadline))
+            .map(|(slot, _)| *slot)
+            .collect();
+        if expired
+            .iter()
+            .any(|slot| self.in_flight[slot].retransmits == MAX_RETRANSMITS)
+        {
+            self.back_off();
+            return Vec::new();
+        }
+
+        let mut out = Vec::new();
+        for slot in expired {
+            let Some(flight) = self.in_flight.get_mut(&slot) else {
+                continue;
+            };
+            flight.retransmits += 1;
+            flight.deadline = Some(now + PHASE_TIMEOUT);
+
+            let prop = Proposal {
+                num: self.num,
+                value: flight.value.clone(),
+            };
+            let resend = if flight.accepting {
+                Message::Accept(slot, prop.clone())
+            } else {
+                Message::Prepare(self.watermark, prop.clone())
+            };
+            resend.paxos_print(id, true, &prop);
+            out.push(resend);
+        }
+        out
+    }
+
+    /// Abandons the current round. We are no longer the leader, so once a
+    /// randomized, exponentially growing backoff is over phase 1 runs again
+    /// from our lowest slot in flight, with a ballot above every promise we
+    /// were told about. Every slot in flight goes back to our own value
+    fn back_off(&mut self) {
+        let highest = self
+            .in_flight
+            .values()
+            .flat_map(|flight| flight.nacks.values())
+            .copied()
+            .max()
+            .unwrap_or(self.num);
+        self.num = self.num.succeed(highest);
+        self.step_down();
+        self.recovered.clear();
+        self.prep_acks.clear();
+        for flight in self.in_flight.values_mut() {
+            if let Some(ref own) = flight.own_value {
+                flight.value = own.clone();
+            }
+            flight.accepting = false;
+            flight.accept_acks.clear();
+            flight.nacks.clear();
+            flight.deadline = None;
+        }
+
+        let ceiling = BASE_BACKOFF
+            .saturating_mul(1 << self.failed_rounds.min(16))
+            .min(MAX_BACKOFF);
+        let wait = rand::thread_rng().gen_range(ceiling / 2..=ceiling);
+        self.backoff_until = Some(Instant::now() + wait);
+        self.failed_rounds += 1;
+    }
+
+    /// Marks a slot of ours as decided. If the value that won was not ours
+    /// it goes back to the front of the line for the next slot
+    fn settle(&mut self, slot: Slot, prop: &Proposal) {
+        let value = &prop.value;
+        self.applied.accept_choice(slot, prop);
+        self.applied.want(slot + 1);
+        self.decided.insert(slot);
+        self.recovered.remove(&slot);
+        let Some(flight) = self.in_flight.remove(&slot) else {
+            return;
+        };
+        if let Some(own) = flight.own_value {
+            if own != *value {
+                self.pending.push_front(own);
+            }
+        }
+        if self.in_flight.is_empty() {
+            self.backoff_until = None;
+        }
+    }
+
+    /// The acceptors compacted everything below `upto`, so all of it is
+    /// decided. Values in flight below that go back in line for slots past
+    /// it, which takes another phase 1, and so does anything still waiting
+    /// on the phase 1 that got turned down
+    pub fn skip_to(&mut self, upto: Slot) {
+        if upto <= self.floor {
+            return;
+        }
+        self.floor = upto;
+        self.decided = self.decided.split_off(&upto);
+        self.recovered = self.recovered.split_off(&upto);
+        self.applied.want(upto);
+        let (lost, kept): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|(slot, flight)| *slot < upto || !flight.accepting);
+        self.in_flight = kept;
+        if lost.is_empty() {
+            return;
+        }
+        for flight in lost.into_values().rev() {
+            if let Some(own) = flight.own_value {
+                self.pending.push_front(own);
+            }
+        }
+        self.step_down();
+        self.prep_acks.clear();
+        self.backoff_until = None;
+    }
+
+    /// Asks the acceptors to renew our lease, every LEASE_RENEW while we lead
+    pub fn renew_lease(&mut self) -> Option<Message> {
+        if !self.leases
+            || !self.leading
+            || self
+                .lease_sent
+                .is_some_and(|sent| sent.elapsed() < LEASE_RENEW)
+        {
+            return None;
+        }
+        self.lease_seq += 1;
+        self.lease_sent = Some(Instant::now());
+        self.lease_grants.clear();
+        Some(Message::Lease(self.num, self.lease_seq))
+    }
+
+    /// Counts an acceptor's grant. Once a phase 2 quorum granted the renewal,
+    /// no phase 1 quorum can promise anyone else until it runs out. It is
+    /// timed from when we asked, which is before any acceptor started its clock
+    pub fn lease_granted(&mut self, num: Ballot, seq: u64, from: PeerId) {
+        let Some(sent) = self.lease_sent else {
+            return;
+        };
+        if !self.leading || num != self.num || seq != self.lease_seq {
+            return;
+        }
+        self.lease_grants.insert(from);
+        if self.quorums.weigh(&self.lease_grants) >= self.quorums.phase2 {
+            self.lease_until = Some(sent + LEASE - LEASE_DRIFT);
+        }
+    }
+
+    /// Stops leading, which ends our lease and any heartbeat round out
+    fn step_down(&mut self) {
+        self.leading = false;
+        self.lease_sent = None;
+        self.lease_grants.clear();
+        self.lease_until = None;
+        self.heartbeat_sent = None;
+    }
+
+    /// True while we lead under a lease that hasn't run out
+    fn has_lease(&self) -> bool {
+        self.leading && self.lease_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Sends a heartbeat round for the reads that came in since the last one
+    /// went out, if we lead without leases. A round nobody answered in time
+    /// counts as unconfirmed
+    pub fn heartbeat(&mut self) -> Option<Message> {
+        if self.leases || !self.leading {
+            return None;
+        }
+        if let Some(sent) = self.heartbeat_sent {
+            if sent.elapsed() < PHASE_TIMEOUT {
+                return None;
+            }
+            self.heartbeat_sent = None;
+            self.unconfirmed = self.heartbeat_seq;
+        }
+        if !self
+            .reads
+            .iter()
+            .any(|read| read.round > self.heartbeat_seq)
+        {
+            return None;
+        }
+        self.heartbeat_seq += 1;
+        self.heartbeat_sent = Some(Instant::now());
+        self.heartbeat_acks.clear();
+        Some(Message::Heartbeat(self.num, self.heartbeat_seq))
+    }
+
+    /// Counts an acceptor's ack. Once a phase 2 quorum hasn't promised anyone
+    /// above our ballot, nobody else had a value chosen before the round went
+    /// out, so every read waiting on it knows where the log stood
+    pub fn heartbeat_acked(&mut self, num: Ballot, seq: u64, from: PeerId) {
+        if self.heartbeat_sent.is_none() || num != self.num || seq != self.heartbeat_seq {
+            return;
+        }
+        self.heartbeat_acks.insert(from);
+        if self.quorums.weigh(&self.heartbeat_acks) >= self.quorums.phase2 {
+            self.confirmed = seq;
+            self.heartbeat_sent = None;
+        }
+    }
+
+    /// One past every slot that could hold a chosen value right now: the ones
+    /// we know are decided, and the ones we are still pushing through
+    fn read_index(&self) -> Slot {
+        [
+            self.decided.last(),
+            self.in_flight.keys().next_back(),
+            self.recovered.keys().next_back(),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .map_or(self.floor, |slot| slot + 1)
+        .max(self.floor)
     }
 
-    /// Acknowledges a prepare_ack from an acceptor. Will return a message
-    /// If it receives a majority of responses and any accepted values,
-    /// It will change its value
+    /// Takes a client's read, to be served off our own copy of the store.
+    /// Only the stable leader can. With leases nobody else can get a value
+    /// chosen while ours lasts, otherwise the next heartbeat round has to
+    /// show nobody did
+    pub fn read(&mut self, id: ReadId, key: &str) -> Result<(), String> {
+        if !self.leading {
+            return Err("Not the leader".into());
+        }
+        if self.leases && !self.has_lease() {
+            return Err("The leader's lease ran out".into());
+        }
+        self.reads.push(Read {
+            id,
+            key: key.to_string(),
+            index: self.read_index(),
+            round: self.heartbeat_seq + 1,
+            since: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Serves each read waiting on us once it is confirmed and every slot
+    /// below its index is applied, so it sees every write chosen before it
+    /// came in. Refused if we stop leading, the lease or heartbeat round
+    /// runs out, or catching up on values we missed takes too long
+    pub fn answered_reads(&mut self) -> Vec<(ReadId, ReadResult)> {
+        if self.reads.is_empty() {
+            return Vec::new();
+        }
+        let leased = self.has_lease();
+        let mut done = Vec::new();
+        for read in std::mem::take(&mut self.reads) {
+            let refusal = if !self.leading {
+                Some("Not the leader anymore")
+            } else if self.leases && !leased {
+                Some("The leader's lease ran out")
+            } else if !self.leases && read.round > self.confirmed && read.round <= self.unconfirmed
+            {
+                Some("A quorum didn't confirm the leader in time")
+            } else if read.since.elapsed() >= READ_TIMEOUT {
+                Some("The leader didn't catch up on the log in time")
+            } else {
+                None
+            };
+            if let Some(refusal) = refusal {
+                done.push((read.id, Err(refusal.to_string())));
+            } else if self.applied.upto() >= read.index
+                && (self.leases || self.confirmed >= read.round)
+            {
+                done.push((read.id, Ok(self.applied.get(&read.key))));
+            } else {
+                self.reads.push(read);
+            }
+        }
+        done
+    }
+
+    /// Acknowledges a prepare_ack from an acceptor. Once a phase 1 quorum has
+    /// promised we become the stable leader, adopting whatever they had
+    /// accepted for our slots in flight and remembering the rest
     pub fn acknowledge_prep(
         &mut self,
+        watermark: Slot,
         from: PeerId,
-        response: Option<Proposal>,
+        promised: Ballot,
+        accepted: Vec<(Slot, Proposal)>,
         id: PeerId,
-    ) -> Option<Message> {
-        if self.chosen {
-            return None;
+    ) -> Vec<Message> {
+        if !self.preparing(watermark, promised) {
+            return Vec::new();
         }
-        self.prep_acks.insert(from, response);
+        self.prep_acks.insert(from, accepted);
 
-        // if we receive from a majority
-        if self.prep_acks.len() > self.quorum_size / 2 && !self.broadcasted_accept {
-            if let Some(highest_prop) = self
-                .prep_acks
-                .values()
-                .filter_map(|o| o.as_ref())
-                .max_by_key(|p| p.num)
-            {
-                self.num = highest_prop.num;
-                self.value = Some(highest_prop.value);
+        // if we receive from a phase 1 quorum
+        if self.quorums.weigh(self.prep_acks.keys()) < self.quorums.phase1 {
+            return Vec::new();
+        }
+        self.leading = true;
+        for (slot, prop) in self.prep_acks.values().flatten() {
+            if self.decided.contains(slot) {
+                continue;
+            }
+            let highest = self.recovered.entry(*slot).or_insert_with(|| prop.clone());
+            if prop.num > highest.num {
+                *highest = prop.clone();
+            }
+        }
+        // every recovered value gets re-accepted under our number
+        for prop in self.recovered.values_mut() {
+            prop.num = self.num;
+        }
+        // and any slot below the highest of them that nobody accepted
+        // anything in gets a no-op, so learners aren't left waiting on a hole
+        let num = self.num;
+        if let Some(&highest) = self.recovered.keys().next_back() {
+            for slot in self.watermark..highest {
+                if self.decided.contains(&slot) || self.in_flight.contains_key(&slot) {
+                    continue;
+                }
+                self.recovered.entry(slot).or_insert(Proposal {
+                    num,
+                    value: Value::Noop,
+                });
             }
+        }
 
-            self.broadcasted_accept = true;
-            let accept_msg = Message::Accept(Proposal {
+        let mut out = Vec::new();
+        for (&slot, flight) in &mut self.in_flight {
+            if let Some(prop) = self.recovered.remove(&slot) {
+                flight.value = prop.value;
+            }
+            flight.accepting = true;
+            flight.nacks.clear();
+            flight.arm();
+            let prop = Proposal {
                 num: self.num,
-                value: self.value?,
-            });
-            accept_msg.paxos_print(id, true, &self.current_prop());
-            Some(accept_msg)
-        } else {
-            None
+                value: flight.value.clone(),
+            };
+            let accept_msg = Message::Accept(slot, prop.clone());
+            accept_msg.paxos_print(id, true, &prop);
+            out.push(accept_msg);
         }
+        out
+    }
+
+    /// Records an acceptor turning down our prepare. Backs off to retry with a
+    /// higher ballot once a quorum of promises is out of reach
+    pub fn reject_prep(&mut self, watermark: Slot, from: PeerId, promised: Ballot) {
+        if !self.preparing(watermark, self.num) {
+            return;
+        }
+        let Some(flight) = self.in_flight.get_mut(&watermark) else {
+            return;
+        };
+        flight.nacks.insert(from, promised);
+        if flight.quorum_lost(&self.quorums) {
+            self.back_off();
+        }
+    }
+
+    /// True while phase 1 for `watermark` under `ballot` is still collecting answers
+    fn preparing(&self, watermark: Slot, ballot: Ballot) -> bool {
+        watermark == self.watermark
+            && ballot == self.num
+            && self.backoff_until.is_none()
+            && !self.leading
+            && self
+                .in_flight
+                .get(&watermark)
+                .is_some_and(|flight| !flight.accepting)
     }
 
     pub fn acknowledge_accept(
         &mut self,
+        slot: Slot,
         from: PeerId,
-        min_proposal: ProposalNum,
+        accepted: Ballot,
         id: PeerId,
     ) -> Option<Message> {
-        if self.chosen {
+        if accepted != self.num {
             return None;
         }
-        self.accept_acks.insert(from, min_proposal);
-
-        // we got a rejection, abort!
-        if min_proposal > self.num {
-            self.num = min_proposal + 1;
-            let redo_prep = Message::Prepare(Proposal {
-                num: self.num,
-                value: self.value?,
-            });
-            redo_prep.paxos_print(id, true, &self.current_prop());
-            return Some(redo_prep);
+        let flight = self.in_flight.get_mut(&slot)?;
+        if !flight.accepting {
+            return None;
         }
+        flight.accept_acks.insert(from);
 
-        if self.accept_acks.len() > self.quorum_size / 2 && !self.chosen {
-            self.chosen = true;
-            let chose_msg = Message::Chosen(Proposal {
-                num: self.num,
-                value: self.value?,
-            });
-            chose_msg.paxos_print(id, true, &self.current_prop());
+        if self.quorums.weigh(&flight.accept_acks) >= self.quorums.phase2 {
+            self.failed_rounds = 0;
+            let prop = self.current_prop(slot);
+            self.settle(slot, &prop);
+            let chose_msg = Message::Chosen(slot, prop.clone());
+            chose_msg.paxos_print(id, true, &prop);
 
             Some(chose_msg)
         } else {
             None
         }
     }
+
+    /// Records an acceptor turning down our accept, meaning someone else has
+    /// prepared since. Gives up leadership once a quorum is out of reach
+    pub fn reject_accept(&mut self, slot: Slot, from: PeerId, promised: Ballot) {
+        let Some(flight) = self.in_flight.get_mut(&slot) else {
+            return;
+        };
+        if !flight.accepting {
+            return;
+        }
+        flight.nacks.insert(from, promised);
+        if flight.quorum_lost(&self.quorums) {
+            self.back_off();
+        }
+    }
 }
 
 pub trait Chooser {
-    fn accept_choice(&mut self, prop: &Proposal);
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal);
 }
 
 impl Chooser for Proposing {
-    fn accept_choice(&mut self, prop: &Proposal) {
-        self.num = prop.num;
-        self.value = Some(prop.value);
-        self.chosen = true;
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal) {
+        // keep our rounds ahead of anyone we've seen win
+        self.num.round = self.num.round.max(prop.num.round);
+        self.settle(slot, prop);
     }
 }
 
 #[derive(Default)]
 pub struct Accepting {
-    min_proposal: ProposalNum,
-    accepted_prop: Option<Proposal>,
+    // One promise covers every slot, so a stable leader only prepares once
+    min_proposal: Ballot,
+    accepted: BTreeMap<Slot, Proposal>,
+    // Only there when we were given a data directory to be durable in
+    wal: Option<Wal>,
+    // Set while a fast round is open: any value can go into an empty slot
+    // from here up under that ballot
+    fast: Option<(Slot, Ballot)>,
+    // Values we've seen chosen, applied in slot order into `state` so we can
+    // snapshot it and throw away everything we accepted below
+    chosen: BTreeMap<Slot, Value>,
+    state: Snapshot,
+    snapshot: Snapshot,
+    // Where the snapshot is kept next to the log, and whether the latest one
+    // still has to be saved there
+    dir: Option<PathBuf>,
+    unsaved: bool,
+    // The proposer we granted a lease to and when it runs out. Until then
+    // nobody else's prepare gets promised. No holder means nobody's does
+    lease_holder: Option<PeerId>,
+    lease_until: Option<Instant>,
+    pub stage: PaxosStage,
 }
 impl Accepting {
-    pub fn prepare(&mut self, prop: &Proposal, id: PeerId) -> Message {
+    pub fn new(stage: PaxosStage) -> Self {
+        Self {
+            stage,
+            ..Default::default()
+        }
+    }
+
+    /// Rebuilds an acceptor from its snapshot and the write-ahead log in
+    /// `dir`, so it comes back up with every promise and accept it made before
+    /// going down
+    pub fn recover(dir: &Path, stage: PaxosStage) -> io::Result<Self> {
+        let (wal, records) = Wal::open(dir, &format!("acceptor{stage}.wal"))?;
+        let snapshot = Snapshot::load(dir, &format!("acceptor{stage}.snap"))?.unwrap_or_default();
+        let mut acceptor = Self::new(stage);
+        for record in records {
+            match record {
+                WalRecord::Promise(num) => acceptor.min_proposal = acceptor.min_proposal.max(num),
+                WalRecord::Accept(slot, prop) => {
+                    acceptor.min_proposal = acceptor.min_proposal.max(prop.num);
+                    acceptor.accepted.insert(slot, prop);
+                }
+            }
+        }
+        // a crash can land between saving a snapshot and compacting the log
+        acceptor.accepted = acceptor.accepted.split_off(&snapshot.upto);
+        acceptor.state = snapshot.clone();
+        acceptor.snapshot = snapshot;
+        acceptor.dir = Some(dir.to_path_buf());
+        acceptor.wal = Some(wal);
+        // leases aren't logged, so if we promised anyone before going down we
+        // sit out one in case we had granted it
+        if acceptor.min_proposal > Ballot::default() {
+            acceptor.lease_until = Some(Instant::now() + LEASE);
+        }
+        Ok(acceptor)
+    }
+
+    /// Makes every promise and accept so far durable. Has to happen before
+    /// the acks for them are sent. A new snapshot gets saved first, and then
+    /// the log is rewritten without anything below it
+    pub fn sync(&mut self) -> io::Result<()> {
+        let Some(ref mut wal) = self.wal else {
+            return Ok(());
+        };
+        if self.unsaved {
+            if let Some(ref dir) = self.dir {
+                self.snapshot
+                    .save(dir, &format!("acceptor{}.snap", self.stage))?;
+            }
+            let records: Vec<WalRecord> = std::iter::once(WalRecord::Promise(self.min_proposal))
+                .chain(
+                    self.accepted
+                        .iter()
+                        .map(|(slot, prop)| WalRecord::Accept(*slot, prop.clone())),
+                )
+                .collect();
+            wal.rewrite(&records)?;
+            self.unsaved = false;
+        }
+        wal.sync()
+    }
+
+    /// Takes a snapshot once enough chosen values have been applied, and
+    /// forgets everything accepted below it
+    pub fn compact(&mut self, id: PeerId) {
+        if !self.state.is_due(&self.snapshot) {
+            return;
+        }
+        self.snapshot = self.state.clone();
+        self.accepted = self.accepted.split_off(&self.snapshot.upto);
+        self.unsaved = true;
+        print_snapshot(id, self.stage, &self.snapshot);
+    }
+
+    /// Everything we have accepted from `from_slot` up, for a learner catching
+    /// up. Slots we already compacted come as our snapshot instead
+    pub fn report(&self, from_slot: Slot) -> Vec<Message> {
+        if from_slot >= self.snapshot.upto {
+            return vec![Message::LearnReply(self.accepted_from(from_slot))];
+        }
+        vec![
+            self.snapshot.chunk(0),
+            Message::LearnReply(self.accepted_from(self.snapshot.upto)),
+        ]
+    }
+
+    /// The next piece of our snapshot for a learner that is partway through it
+    pub fn snapshot_chunk(&self, upto: Slot, offset: u64) -> Option<Message> {
+        self.snapshot.serve(upto, offset)
+    }
+
+    fn accepted_from(&self, from_slot: Slot) -> Vec<(Slot, Proposal)> {
+        self.accepted
+            .range(from_slot..)
+            .map(|(slot, prop)| (*slot, prop.clone()))
+            .collect()
+    }
+
+    /// True while a lease keeps `proposer` from getting promises
+    fn leased_away(&self, proposer: PeerId) -> bool {
+        self.lease_until.is_some_and(|until| Instant::now() < until)
+            && self.lease_holder != Some(proposer)
+    }
+
+    /// Grants the stable leader a lease, which also promises its ballot.
+    /// Refused for a ballot lower than one we promised, or while someone
+    /// else holds a lease
+    pub fn grant_lease(&mut self, num: Ballot, seq: u64) -> Option<Message> {
+        if num < self.min_proposal || self.leased_away(num.proposer) {
+            return None;
+        }
+        if num > self.min_proposal {
+            self.min_proposal = num;
+            if let Some(ref mut wal) = self.wal {
+                wal.record(&WalRecord::Promise(num));
+            }
+        }
+        self.lease_holder = Some(num.proposer);
+        self.lease_until = Some(Instant::now() + LEASE);
+        Some(Message::LeaseGrant(num, seq))
+    }
+
+    /// Acks a leader's heartbeat if we haven't promised anyone above it.
+    /// Nothing gets promised or logged for it
+    pub fn heartbeat(&self, num: Ballot, seq: u64) -> Option<Message> {
+        if num < self.min_proposal || self.leased_away(num.proposer) {
+            return None;
+        }
+        Some(Message::HeartbeatAck(num, seq))
+    }
+
+    /// Promises `prop.num` and reports everything accepted from `watermark` up,
+    /// unless we already promised a higher ballot or leased it to someone else
+    pub fn prepare(&mut self, watermark: Slot, prop: &Proposal, id: PeerId) -> Message {
+        if watermark < self.snapshot.upto {
+            return Message::Compacted(self.snapshot.upto);
+        }
+        if prop.num < self.min_proposal || self.leased_away(prop.num.proposer) {
+            let msg = Message::PrepareNack {
+                watermark,
+                promised: self.min_proposal,
+            };
+            msg.paxos_print(id, true, prop);
+            return msg;
+        }
+
         // if n > minProposal then minProposal = n
-        self.min_proposal = self.min_proposal.max(prop.num);
-        let msg = Message::PrepareAck(self.accepted_prop.clone());
+        if prop.num > self.min_proposal {
+            self.min_proposal = prop.num;
+            if let Some(ref mut wal) = self.wal {
+                wal.record(&WalRecord::Promise(prop.num));
+            }
+        }
+        let msg = Message::PrepareAck(watermark, prop.num, self.accepted_from(watermark));
         msg.paxos_print(id, true, prop);
         msg
     }
 
-    pub fn accept(&mut self, prop: &Proposal, id: PeerId) -> Message {
-        if prop.num > self.min_proposal {
+    pub fn accept(&mut self, slot: Slot, prop: &Proposal, id: PeerId) -> Message {
+        if slot < self.snapshot.upto {
+            return Message::Compacted(self.snapshot.upto);
+        }
+        let msg = if prop.num >= self.min_proposal {
             self.min_proposal = prop.num;
-            if let Some(ref mut prop) = self.accepted_prop {
-                prop.num = self.min_proposal;
-            } else {
-                self.accepted_prop = Some(prop.clone());
+            self.accepted.insert(slot, prop.clone());
+            if let Some(ref mut wal) = self.wal {
+                wal.record(&WalRecord::Accept(slot, prop.clone()));
+            }
+            Message::AcceptAck(slot, prop.num)
+        } else {
+            Message::AcceptNack {
+                slot,
+                promised: self.min_proposal,
             }
-        }
-        let msg = Message::AcceptAck {
-            min_proposal: self.min_proposal,
         };
 
         msg.paxos_print(id, true, prop);
         msg
     }
+
+    /// Opens a fast round from `from` up under `num`, which the coordinator
+    /// already holds a phase 1 quorum for. Refused if we promised higher since
+    pub fn open_fast(&mut self, from: Slot, num: Ballot) -> Option<Message> {
+        if num < self.min_proposal {
+            return Some(Message::AcceptNack {
+                slot: from,
+                promised: self.min_proposal,
+            });
+        }
+        if num > self.min_proposal {
+            self.min_proposal = num;
+            if let Some(ref mut wal) = self.wal {
+                wal.record(&WalRecord::Promise(num));
+            }
+        }
+        self.fast = Some((from, num));
+        None
+    }
+
+    /// Takes a value straight from a proposer. With a fast round open it goes
+    /// into the first slot that hasn't accepted anything under the round yet,
+    /// and the vote goes to the coordinator. Otherwise the value is handed on to
+    /// the coordinator to get a round going
+    pub fn fast_accept(&mut self, value: Value, id: PeerId) -> Message {
+        let Some((from, num)) = self.fast.filter(|(_, num)| *num == self.min_proposal) else {
+            return Message::FastPropose(value);
+        };
+
+        // a resent value we already took keeps its slot
+        let from = from.max(self.snapshot.upto);
+        let mut slot = from;
+        for (accepted_slot, prop) in self.accepted.range(from..) {
+            if prop.num == num && prop.value == value {
+                let msg = Message::FastAccepted(*accepted_slot, prop.clone());
+                msg.paxos_print(id, true, prop);
+                return msg;
+            }
+            if *accepted_slot == slot && prop.num >= num {
+                slot += 1;
+            }
+        }
+
+        let prop = Proposal { num, value };
+        self.accepted.insert(slot, prop.clone());
+        if let Some(ref mut wal) = self.wal {
+            wal.record(&WalRecord::Accept(slot, prop.clone()));
+        }
+        let msg = Message::FastAccepted(slot, prop.clone());
+        msg.paxos_print(id, true, &prop);
+        msg
+    }
 }
 impl Chooser for Accepting {
-    fn accept_choice(&mut self, _prop: &Proposal) {}
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal) {
+        if slot >= self.state.upto {
+            self.chosen.insert(slot, prop.value.clone());
+        }
+        while let Some(value) = self.chosen.remove(&self.state.upto) {
+            self.state.apply(&value);
+        }
+    }
 }
 
-#[derive(Default)]
 pub struct Learning {
-    chosen: bool,
+    // Every decided value we know of past the last snapshot. Only the ones
+    // with no gap before them have been delivered
+    decided: BTreeMap<Slot, Proposal>,
+    // What each acceptor told us it accepted for slots we're catching up on
+    reports: BTreeMap<Slot, HashMap<PeerId, Proposal>>,
+    quorums: Quorums,
+    next_query: Option<Instant>,
+    // Key-value commands and client sessions applied in slot order as they
+    // get delivered. Its `upto` is the next slot to hand off in order
+    state: Snapshot,
+    // The last copy of `state` we took. Nothing below it is kept around
+    snapshot: Snapshot,
+    // A newer snapshot than ours that we are partway through receiving
+    transfer: Option<Transfer>,
+    // Set for a proposer's copy of the applied log, which prints nothing and
+    // only asks around once it is missing something. Everything below
+    // `wanted` is, even without a gap to show for it
+    quiet: bool,
+    wanted: Slot,
+    // What the values a quiet copy applied returned, until the proposer takes them
+    outcomes: Vec<Outcome>,
+    pub stage: PaxosStage,
+}
+impl Learning {
+    pub fn new(quorums: Quorums, stage: PaxosStage) -> Self {
+        Self {
+            decided: BTreeMap::new(),
+            reports: BTreeMap::new(),
+            quorums,
+            next_query: None,
+            state: Snapshot::default(),
+            snapshot: Snapshot::default(),
+            transfer: None,
+            quiet: false,
+            wanted: 0,
+            outcomes: Vec::new(),
+            stage,
+        }
+    }
+
+    /// A proposer's own copy of the stage's applied log. It catches up the
+    /// way a learner does, without printing anything or asking around
+    /// while it has all it needs
+    pub fn quiet(quorums: Quorums, stage: PaxosStage) -> Self {
+        Self {
+            quiet: true,
+            ..Self::new(quorums, stage)
+        }
+    }
+
+    /// The first slot not applied yet
+    pub fn upto(&self) -> Slot {
+        self.state.upto
+    }
+
+    /// A key's value in the applied store
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.store.get(key)
+    }
+
+    /// The client's last request in the session table
+    pub fn last_request(&self, client: ClientId) -> Option<LastRequest> {
+        self.state.sessions.last(client)
+    }
+
+    /// Values applied since the last call, with what each returned
+    pub fn outcomes(&mut self) -> Vec<Outcome> {
+        std::mem::take(&mut self.outcomes)
+    }
+
+    /// Makes sure everything below `upto` gets applied, asking around for
+    /// it if it doesn't show up on its own
+    pub fn want(&mut self, upto: Slot) {
+        self.wanted = self.wanted.max(upto);
+    }
+
+    /// True while something decided can't be applied yet: a slot past a gap,
+    /// a snapshot coming in, or slots we were told we need
+    fn is_behind(&self) -> bool {
+        self.state.upto < self.wanted
+            || self.transfer.is_some()
+            || self.decided.range(self.state.upto..).next().is_some()
+    }
+
+    pub fn reconfigure(&mut self, quorums: Quorums) {
+        self.quorums = quorums;
+    }
+
+    /// Delivers every decided slot that no longer has a gap before it, and
+    /// applies the key-value commands among them, batches unpacked in order.
+    /// Prints the whole map after any that did, and takes a snapshot once
+    /// enough has been applied
+    pub fn deliver(&mut self, id: PeerId) {
+        let json = |result: Option<String>| {
+            result.map_or_else(|| "null".to_string(), |v| Value::Text(v).json())
+        };
+        let mut applied = false;
+        while let Some(prop) = self.decided.get(&self.state.upto) {
+            let slot = self.state.upto;
+            let results = self.state.apply(&prop.value);
+            if self.quiet {
+                self.outcomes
+                    .extend(results.into_iter().map(|(value, result)| {
+                        let result = match result {
+                            Applied::Skipped => None,
+                            Applied::Command(result) | Applied::Duplicate(_, _, result) => result,
+                        };
+                        (slot, value.clone(), result)
+                    }));
+                continue;
+            }
+            eprintln!(
+                "{{\"peer_id\": {id}, \"action\": \"delivered\", \"slot\": {slot}, \"message_value\": {}}}",
+                prop.value.json()
+            );
+            for (value, result) in results {
+                match result {
+                    Applied::Skipped => {}
+                    Applied::Command(result) => {
+                        eprintln!(
+                            "{{\"peer_id\": {id}, \"action\": \"applied\", \"slot\": {slot}, \"command\": {}, \"result\": {}}}",
+                            value.json(),
+                            json(result)
+                        );
+                        applied = true;
+                    }
+                    Applied::Duplicate(client, seq, result) => eprintln!(
+                        "{{\"peer_id\": {id}, \"action\": \"duplicate\", \"slot\": {slot}, \"client\": {client}, \"seq\": {seq}, \"result\": {}}}",
+                        json(result)
+                    ),
+                }
+            }
+        }
+        if applied {
+            eprintln!(
+                "{{\"peer_id\": {id}, \"action\": \"store\", \"stage\": {}, \"entries\": {}}}",
+                self.stage,
+                self.state.store.json()
+            );
+        }
+        if self.state.is_due(&self.snapshot) {
+            self.snapshot = self.state.clone();
+            self.decided = self.decided.split_off(&self.snapshot.upto);
+            if !self.quiet {
+                print_snapshot(id, self.stage, &self.snapshot);
+            }
+        }
+    }
+
+    /// Takes a chunk of a snapshot from a peer that already threw away slots
+    /// we are still missing, and asks it for the next one. A newer snapshot
+    /// than the one coming in starts over with that one and its sender.
+    /// Chunks of the same one from anyone else are ignored
+    pub fn receive_chunk(
+        &mut self,
+        from: PeerId,
+        upto: Slot,
+        offset: u64,
+        total: u64,
+        data: &[u8],
+        id: PeerId,
+    ) -> Option<Message> {
+        if upto <= self.state.upto || self.transfer.as_ref().is_some_and(|t| t.upto > upto) {
+            return None;
+        }
+        if self.transfer.as_ref().is_none_or(|t| t.upto < upto) {
+            self.transfer = Some(Transfer::new(upto, from, total));
+        }
+        let transfer = self.transfer.as_mut()?;
+        match transfer.receive(from, offset, data) {
+            Ok(Received::Ignored) => None,
+            Ok(Received::Progress) => Some(Message::SnapshotRequest {
+                upto,
+                offset: transfer.offset(),
+            }),
+            Ok(Received::Done(snapshot)) => {
+                self.transfer = None;
+                self.install(snapshot, id);
+                None
+            }
+            Err(e) => {
+                eprintln!("{{\"peer_id\": {id}, \"action\": \"bad_snapshot\", \"stage\": {}, \"upto\": {upto}, \"error\": \"{e}\"}}", self.stage);
+                self.transfer = None;
+                None
+            }
+        }
+    }
+
+    /// Takes on a snapshot that came in whole. Only ever built from chosen
+    /// values, so one peer's is enough
+    fn install(&mut self, snapshot: Snapshot, id: PeerId) {
+        if snapshot.upto <= self.state.upto {
+            return;
+        }
+        self.state = snapshot.clone();
+        self.decided = self.decided.split_off(&snapshot.upto);
+        self.reports = self.reports.split_off(&snapshot.upto);
+        if !self.quiet {
+            eprintln!(
+                "{{\"peer_id\": {id}, \"action\": \"installed_snapshot\", \"stage\": {}, \"upto\": {}, \"entries\": {}}}",
+                self.stage,
+                snapshot.upto,
+                snapshot.store.json()
+            );
+        }
+        self.snapshot = snapshot;
+    }
+
+    /// Periodically asks the stage's acceptors and learners for everything
+    /// from our first undelivered slot up, in case we missed a Chosen
+    /// broadcast. While a snapshot is coming in we ask its sender for the
+    /// rest of it instead, and start over with whoever answers the next query
+    /// once that sender has gone quiet for too long. A proposer's copy only
+    /// asks once it has been behind for a while
+    pub fn on_timer(&mut self, peers: &PeerList) -> Option<(Message, Vec<PeerId>)> {
+        let now = Instant::now();
+        if self.quiet && !self.is_behind() {
+            self.next_query = None;
+            return None;
+        }
+        if self.quiet && self.next_query.is_none() {
+            // give the Chosen broadcasts a chance first
+            self.next_query = Some(now + CATCHUP_INTERVAL);
+            return None;
+        }
+        if self.next_query.is_some_and(|at| now < at) {
+            return None;
+        }
+        self.next_query = Some(now + CATCHUP_INTERVAL);
+        if let Some(ref transfer) = self.transfer {
+            if transfer.quiet_for() < TRANSFER_STALL {
+                let request = Message::SnapshotRequest {
+                    upto: transfer.upto,
+                    offset: transfer.offset(),
+                };
+                return Some((request, vec![transfer.from]));
+            }
+            eprintln!(
+                "{{\"peer_id\": {}, \"action\": \"snapshot_stalled\", \"stage\": {}, \"upto\": {}, \"from\": {}}}",
+                peers.id(),
+                self.stage,
+                transfer.upto,
+                transfer.from
+            );
+            self.transfer = None;
+        }
+        Some((
+            Message::LearnQuery(self.state.upto),
+            peers.acceptors_and_learners(self.stage),
+        ))
+    }
+
+    /// What the stage's first slot decided, once we delivered it or installed
+    /// a snapshot past it
+    pub fn decision(&self) -> Option<&Value> {
+        self.state.first.as_ref()
+    }
+
+    /// The next piece of our snapshot for another learner partway through it
+    pub fn snapshot_chunk(&self, upto: Slot, offset: u64) -> Option<Message> {
+        self.snapshot.serve(upto, offset)
+    }
+
+    /// Answers another learner's query with every decided slot we have from
+    /// `from_slot` up, after our snapshot if they are asking about slots below it
+    pub fn answer(&self, from_slot: Slot) -> Vec<Message> {
+        let mut out = Vec::new();
+        if from_slot < self.snapshot.upto {
+            out.push(self.snapshot.chunk(0));
+        }
+        out.extend(
+            self.decided
+                .range(from_slot..)
+                .map(|(slot, prop)| Message::Chosen(*slot, prop.clone())),
+        );
+        out
+    }
+
+    /// Takes in what an acceptor has accepted. A slot counts as decided once a
+    /// phase 2 quorum of acceptors report accepting the same ballot and value
+    /// for it. Fast stages can't tell a fast ballot from a classic one here, so
+    /// they wait for whichever quorum is bigger
+    pub fn report(&mut self, from: PeerId, accepted: Vec<(Slot, Proposal)>) {
+        let needed = self
+            .quorums
+            .fast
+            .map_or(self.quorums.phase2, |fast| fast.max(self.quorums.phase2));
+        for (slot, prop) in accepted {
+            if slot < self.state.upto || self.decided.contains_key(&slot) {
+                continue;
+            }
+            let reports = self.reports.entry(slot).or_default();
+            reports.insert(from, prop.clone());
+            let agreeing = self.quorums.weigh(
+                reports
+                    .iter()
+                    .filter(|(_, p)| p.num == prop.num && p.value == prop.value)
+                    .map(|(id, _)| id),
+            );
+            if agreeing >= needed {
+                self.reports.remove(&slot);
+                self.decided.insert(slot, prop);
+            }
+        }
+    }
 }
 impl Chooser for Learning {
-    fn accept_choice(&mut self, _prop: &Proposal) {
-        self.chosen = true;
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal) {
+        self.reports.remove(&slot);
+        if slot >= self.state.upto {
+            self.decided.entry(slot).or_insert_with(|| prop.clone());
+        }
     }
 }
 
+/// Prints that a role took a snapshot and can forget everything below it
+fn print_snapshot(id: PeerId, stage: PaxosStage, snapshot: &Snapshot) {
+    eprintln!(
+        "{{\"peer_id\": {id}, \"action\": \"snapshot\", \"stage\": {stage}, \"upto\": {}}}",
+        snapshot.upto
+    );
+}
+
 pub enum PaxosRole {
-    Prop(Proposing),
+    Prop(Box<Proposing>),
     Acc(Accepting),
     Learn(Learning),
+    // Proposers of a fast stage, see src/state/fast.rs
+    Coord(Box<Coordinating>),
+    Submit(Submitting),
+}
+impl PaxosRole {
+    /// The role's copy of the stage's applied log, if it keeps one: a
+    /// learner's, or the one a proposer answers clients and serves reads off
+    fn applier(&mut self) -> Option<&mut Learning> {
+        match self {
+            Self::Learn(learner) => Some(learner),
+            Self::Prop(p) => Some(&mut p.applied),
+            Self::Coord(c) => Some(&mut c.applied),
+            Self::Submit(s) => Some(&mut s.applied),
+            Self::Acc(_) => None,
+        }
+    }
 }
 impl Chooser for PaxosRole {
-    fn accept_choice(&mut self, prop: &Proposal) {
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal) {
+        match self {
+            Self::Acc(a) => a.accept_choice(slot, prop),
+            Self::Learn(l) => l.accept_choice(slot, prop),
+            Self::Prop(p) => p.accept_choice(slot, prop),
+            Self::Coord(c) => c.accept_choice(slot, prop),
+            Self::Submit(s) => s.accept_choice(slot, prop),
+        }
+    }
+}
+
+/// Who a fast stage coordinator's message goes to. Decisions go to everyone,
+/// everything else to the stage's acceptors
+fn coordinator_recipients(peers: &PeerList, msg: &Message, stage: PaxosStage) -> Vec<PeerId> {
+    match msg {
+        Message::Chosen(..) => peers.everyone(),
+        _ => peers.acceptors(stage),
+    }
+}
+
+impl ConsensusEngine for PaxosRole {
+    /// Returns true if we are the proposer at the current stage of the system
+    fn can_propose(&self) -> bool {
+        match self {
+            Self::Prop(p) => p.has_room(),
+            Self::Coord(c) => !c.has_begun(),
+            Self::Submit(s) => !s.has_begun(),
+            _ => false,
+        }
+    }
+
+    /// Hands the value to whichever proposer we are, which sends it on to the
+    /// stage's acceptors
+    fn propose(&mut self, v: Value, peers: &PeerList) -> Outbox {
+        let id = peers.id();
+        let (to_send, stage) = match self {
+            Self::Prop(p) => (p.propose(v, id), p.stage),
+            Self::Coord(c) => (c.propose(v, id), c.stage),
+            Self::Submit(s) => (vec![s.propose(v)], s.stage),
+            _ => return Vec::new(),
+        };
+        to_send
+            .into_iter()
+            .map(|msg| (msg, peers.acceptors(stage)))
+            .collect()
+    }
+
+    fn on_message(&mut self, from: PeerId, msg: &Message, peers: &PeerList) -> Outbox {
+        let id = peers.id();
+        let mut out = Vec::new();
+        match (msg, &mut *self) {
+            (Message::Prepare(slot, prop), PaxosRole::Acc(ref mut acc)) => {
+                msg.paxos_print(id, false, prop);
+
+                let msg = acc.prepare(*slot, prop, id);
+                out.push((msg, vec![from]));
+            }
+
+            (Message::PrepareAck(slot, promised, response), PaxosRole::Prop(ref mut prop)) => {
+                msg.paxos_print(id, false, &prop.current_prop(*slot));
+
+                for msg in prop.acknowledge_prep(*slot, from, *promised, response.clone(), id) {
+                    out.push((msg, peers.acceptors(prop.stage)));
+                }
+            }
+
+            (
+                Message::PrepareNack {
+                    watermark,
+                    promised,
+                },
+                PaxosRole::Prop(ref mut prop),
+            ) => {
+                msg.paxos_print(id, false, &prop.current_prop(*watermark));
+
+                prop.reject_prep(*watermark, from, *promised);
+            }
+
+            (Message::Accept(slot, prop), PaxosRole::Acc(ref mut acceptor)) => {
+                msg.paxos_print(id, false, prop);
+                let msg = acceptor.accept(*slot, prop, id);
+                out.push((msg, vec![from]));
+            }
+
+            (Message::AcceptAck(slot, accepted), PaxosRole::Prop(ref mut proposer)) => {
+                msg.paxos_print(id, false, &proposer.current_prop(*slot));
+                if let Some(msg) = proposer.acknowledge_accept(*slot, from, *accepted, id) {
+                    out.push((msg, peers.everyone()));
+                }
+            }
+
+            (Message::AcceptNack { slot, promised }, PaxosRole::Prop(ref mut proposer)) => {
+                msg.paxos_print(id, false, &proposer.current_prop(*slot));
+                proposer.reject_accept(*slot, from, *promised);
+            }
+
+            (Message::Chosen(slot, prop), role) => {
+                msg.paxos_print(id, false, prop);
+                role.accept_choice(*slot, prop);
+                if let PaxosRole::Acc(acceptor) = role {
+                    acceptor.compact(id);
+                }
+            }
+            (Message::LearnQuery(from_slot), PaxosRole::Acc(ref acceptor)) => {
+                for msg in acceptor.report(*from_slot) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            (
+                Message::SnapshotChunk {
+                    upto,
+                    offset,
+                    total,
+                    data,
+                },
+                role,
+            ) => {
+                if let Some(msg) = role.applier().and_then(|learner| {
+                    learner.receive_chunk(from, *upto, *offset, *total, data, id)
+                }) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            (Message::SnapshotRequest { upto, offset }, PaxosRole::Acc(ref acceptor)) => {
+                if let Some(msg) = acceptor.snapshot_chunk(*upto, *offset) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            (Message::SnapshotRequest { upto, offset }, PaxosRole::Learn(ref learner)) => {
+                if let Some(msg) = learner.snapshot_chunk(*upto, *offset) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            (Message::Compacted(upto), PaxosRole::Prop(ref mut prop)) => prop.skip_to(*upto),
+
+            (Message::Lease(num, seq), PaxosRole::Acc(ref mut acceptor)) => {
+                if let Some(msg) = acceptor.grant_lease(*num, *seq) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            (Message::LeaseGrant(num, seq), PaxosRole::Prop(ref mut prop)) => {
+                prop.lease_granted(*num, *seq, from);
+            }
+
+            (Message::Heartbeat(num, seq), PaxosRole::Acc(ref acceptor)) => {
+                if let Some(msg) = acceptor.heartbeat(*num, *seq) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            (Message::HeartbeatAck(num, seq), PaxosRole::Prop(ref mut prop)) => {
+                prop.heartbeat_acked(*num, *seq, from);
+            }
+
+            (Message::Compacted(upto), PaxosRole::Coord(ref mut coord)) => {
+                for msg in coord.skip_to(*upto, id) {
+                    out.push((msg, peers.acceptors(coord.stage)));
+                }
+            }
+
+            (Message::LearnQuery(from_slot), PaxosRole::Learn(ref learner)) => {
+                for msg in learner.answer(*from_slot) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            (Message::LearnReply(accepted), role) => {
+                if let Some(learner) = role.applier() {
+                    learner.report(from, accepted.clone());
+                }
+            }
+
+            (Message::AnyValue(from_slot, num), PaxosRole::Acc(ref mut acceptor)) => {
+                if let Some(msg) = acceptor.open_fast(*from_slot, *num) {
+                    out.push((msg, vec![from]));
+                }
+            }
+
+            // votes, and values we can't take yet, both end up with the coordinator
+            (Message::FastPropose(value), PaxosRole::Acc(ref mut acceptor)) => {
+                let msg = acceptor.fast_accept(value.clone(), id);
+                if let Some(coordinator) = peers.coordinator(acceptor.stage) {
+                    out.push((msg, vec![coordinator]));
+                }
+            }
+
+            (Message::FastPropose(value), PaxosRole::Coord(ref mut coord)) => {
+                if let Some(msg) = coord.forwarded(value.clone(), id) {
+                    out.push((msg, peers.acceptors(coord.stage)));
+                }
+            }
+
+            (Message::FastAccepted(slot, prop), PaxosRole::Coord(ref mut coord)) => {
+                msg.paxos_print(id, false, prop);
+                let stage = coord.stage;
+                for msg in coord.fast_accepted(*slot, from, prop.clone(), id) {
+                    let to = coordinator_recipients(peers, &msg, stage);
+                    out.push((msg, to));
+                }
+            }
+
+            (Message::PrepareAck(slot, promised, response), PaxosRole::Coord(ref mut coord)) => {
+                msg.paxos_print(id, false, &coord.current_prop());
+                let stage = coord.stage;
+                for msg in coord.acknowledge_prep(*slot, from, *promised, response.clone(), id) {
+                    out.push((msg, peers.acceptors(stage)));
+                }
+            }
+
+            (Message::AcceptAck(slot, accepted), PaxosRole::Coord(ref mut coord)) => {
+                msg.paxos_print(id, false, &coord.current_prop());
+                if let Some(msg) = coord.acknowledge_accept(*slot, from, *accepted, id) {
+                    out.push((msg, peers.everyone()));
+                }
+            }
+
+            (
+                Message::PrepareNack { promised, .. } | Message::AcceptNack { promised, .. },
+                PaxosRole::Coord(ref mut coord),
+            ) => {
+                msg.paxos_print(id, false, &coord.current_prop());
+                if let Some(msg) = coord.preempted(*promised, id) {
+                    out.push((msg, peers.acceptors(coord.stage)));
+                }
+            }
+
+            // another of our roles in this stage is who it was for
+            _ => {}
+        }
+
+        if let Some(learner) = self.applier() {
+            learner.deliver(id);
+        }
+
+        // once a slot is settled the proposer moves onto its next value
+        if let PaxosRole::Prop(ref mut p) = self {
+            for msg in p.resume(id) {
+                out.push((msg, peers.acceptors(p.stage)));
+            }
+        }
+        out
+    }
+
+    /// Lets a proposer resend a phase that has waited too long, or send the
+    /// prepare it was backing off on. Learners use it to catch up on missed slots
+    fn on_timer(&mut self, peers: &PeerList) -> Outbox {
+        let id = peers.id();
+        let mut out = Vec::new();
+        match self {
+            PaxosRole::Prop(ref mut p) => {
+                let mut msgs = p.on_timer(id);
+                msgs.extend(p.renew_lease());
+                msgs.extend(p.heartbeat());
+                for msg in msgs {
+                    out.push((msg, peers.acceptors(p.stage)));
+                }
+                out.extend(p.applied.on_timer(peers));
+            }
+            PaxosRole::Learn(ref mut l) => {
+                out.extend(l.on_timer(peers));
+            }
+            PaxosRole::Coord(ref mut c) => {
+                let stage = c.stage;
+                for msg in c.on_timer(id) {
+                    let to = coordinator_recipients(peers, &msg, stage);
+                    out.push((msg, to));
+                }
+                out.extend(c.applied.on_timer(peers));
+            }
+            PaxosRole::Submit(ref mut s) => {
+                for msg in s.on_timer() {
+                    out.push((msg, peers.acceptors(s.stage)));
+                }
+                out.extend(s.applied.on_timer(peers));
+            }
+            PaxosRole::Acc(_) => {}
+        }
+        out
+    }
+
+    fn reconfigure(&mut self, quorums: Quorums) {
         match self {
-            Self::Acc(a) => a.accept_choice(prop),
-            Self::Learn(l) => l.accept_choice(prop),
-            Self::Prop(p) => p.accept_choice(prop),
+            Self::Prop(p) => {
+                p.applied.reconfigure(quorums.clone());
+                p.quorums = quorums;
+            }
+            Self::Coord(c) => c.reconfigure(quorums),
+            Self::Learn(l) => l.reconfigure(quorums),
+            Self::Submit(s) => s.applied.reconfigure(quorums),
+            Self::Acc(_) => {}
         }
     }
+
+    /// Only the stable leader of a classic stage serves reads
+    fn read(&mut self, id: ReadId, key: &str, _peers: &PeerList) -> Result<Outbox, String> {
+        match self {
+            Self::Prop(p) => p.read(id, key).map(|()| Vec::new()),
+            _ => Err("Only a Multi-Paxos leader serves reads".into()),
+        }
+    }
+
+    fn answered_reads(&mut self) -> Vec<(ReadId, ReadResult)> {
+        match self {
+            Self::Prop(p) => p.answered_reads(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn applied(&mut self) -> Vec<Outcome> {
+        self.applier().map_or_else(Vec::new, Learning::outcomes)
+    }
+
+    fn last_request(&self, client: ClientId) -> Option<LastRequest> {
+        match self {
+            Self::Prop(p) => p.applied.last_request(client),
+            Self::Coord(c) => c.applied.last_request(client),
+            Self::Submit(s) => s.applied.last_request(client),
+            Self::Acc(_) | Self::Learn(_) => None,
+        }
+    }
+
+    fn decision(&self) -> Option<&Value> {
+        match self {
+            Self::Learn(learner) => learner.decision(),
+            _ => None,
+        }
+    }
+
+    /// Nothing an acceptor says can leave before it is on disk
+    fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Self::Acc(acc) => acc.sync(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    #[test]
+    fn acknowledge_prep_fills_holes_below_recovered_slots_with_noops() {
+        let quorums = Quorums::majority(HashMap::from([(2, 1), (3, 1), (4, 1)]));
+        let mut proposer = Proposing::new(quorums, 1, 1, 1, false);
+        let prepare = proposer.propose(text("ours"), 1);
+        assert!(matches!(prepare[..], [Message::Prepare(0, _)]));
+        let num = proposer.num;
+
+        // a leader before us got slots 1 and 4 accepted, but not 2 or 3
+        let old = |value| Proposal {
+            num: Ballot {
+                round: 0,
+                proposer: 5,
+            },
+            value: text(value),
+        };
+        proposer.acknowledge_prep(0, 2, num, vec![(1, old("one"))], 1);
+        proposer.acknowledge_prep(0, 3, num, vec![(4, old("four"))], 1);
+
+        let recovered: Vec<(Slot, Value)> = proposer
+            .recovered
+            .iter()
+            .map(|(slot, prop)| (*slot, prop.value.clone()))
+            .collect();
+        assert_eq!(
+            recovered,
+            [
+                (1, text("one")),
+                (2, Value::Noop),
+                (3, Value::Noop),
+                (4, text("four"))
+            ]
+        );
+        assert!(proposer.recovered.values().all(|prop| prop.num == num));
+        // nothing new goes in until every one of them is finished
+        assert!(!proposer.has_room());
+    }
+
+    #[test]
+    fn a_proposers_applied_log_is_behind_until_every_decided_slot_is_in() {
+        let quorums = Quorums::majority(HashMap::from([(2, 1), (3, 1), (4, 1)]));
+        let mut proposer = Proposing::new(quorums, 1, 1, 1, false);
+        let chosen = |value| Proposal {
+            num: Ballot::default(),
+            value: text(value),
+        };
+        assert!(!proposer.applied.is_behind());
+
+        // slot 0's Chosen went missing
+        proposer.accept_choice(1, &chosen("one"));
+        proposer.applied.deliver(1);
+        assert!(proposer.applied.is_behind());
+        proposer.accept_choice(0, &chosen("zero"));
+        proposer.applied.deliver(1);
+        assert_eq!(proposer.applied.upto(), 2);
+        assert!(!proposer.applied.is_behind());
+
+        // the acceptors compacted slots we never heard about
+        proposer.skip_to(5);
+        assert!(proposer.applied.is_behind());
+    }
+
+    #[test]
+    fn a_quiet_copy_hands_back_what_each_value_returned() {
+        let quorums = Quorums::majority(HashMap::from([(2, 1), (3, 1), (4, 1)]));
+        let mut applied = Learning::quiet(quorums, 1);
+        let line = |line| Value::from_line(line).expect("Parses");
+        let chosen = |value| Proposal {
+            num: Ballot::default(),
+            value,
+        };
+        let batch = Value::Batch(vec![line("kv:put a 1"), text("hi")]);
+        applied.accept_choice(1, &chosen(line("kv:get a")));
+        applied.accept_choice(0, &chosen(batch));
+        applied.deliver(1);
+        assert_eq!(
+            applied.outcomes(),
+            [
+                (0, line("kv:put a 1"), None),
+                (0, text("hi"), None),
+                (1, line("kv:get a"), Some("1".to_string())),
+            ]
+        );
+        assert!(applied.outcomes().is_empty());
+    }
 }
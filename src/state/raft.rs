@@ -0,0 +1,599 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io, iter,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    engine::{ConsensusEngine, Outbox, Outcome},
+    kv::{ClientId, LastRequest},
+    paxos::{Ballot, Chooser, Learning, PaxosStage, Proposal, Quorums, Slot, Value},
+    wal::Wal,
+};
+use crate::{
+    messaging::{dist_types::PeerId, Message},
+    setup::hostsfile::PeerList,
+};
+
+/// Raft's election term. A log entry is kept as a `Proposal` whose ballot is
+/// the term it was appended in and the leader that appended it
+pub type Term = u64;
+
+/// How often a leader reminds its followers it is still around
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// Followers that hear nothing for this long (up to twice it, at random) stand
+/// for election themselves
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+/// How long a client waits to see its value committed before sending it again
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// Most entries a single AppendEntries carries
+const MAX_ENTRIES: usize = 64;
+
+/// A single change to a server's persistent state, in the order it happened
+#[derive(Serialize, Deserialize, Debug)]
+enum RaftRecord {
+    Term(Term, Option<PeerId>),
+    Append(Slot, Proposal),
+    Truncate(Slot),
+}
+
+enum Standing {
+    Follower,
+    Candidate(HashSet<PeerId>),
+    // Where each follower's next entry starts, and how much of our log it holds
+    Leader {
+        next: HashMap<PeerId, Slot>,
+        matched: HashMap<PeerId, Slot>,
+    },
+}
+
+/// A Raft server. The stage's acceptors in the hostsfile are its servers
+pub struct Server {
+    id: PeerId,
+    term: Term,
+    voted_for: Option<PeerId>,
+    log: Vec<Proposal>,
+    // Every entry below this is committed
+    commit: Slot,
+    standing: Standing,
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+    // Elections and commits both need a plain majority of the votes, so two
+    // leaders can never be elected in one term
+    quorums: Quorums,
+    wal: Option<Wal<RaftRecord>>,
+    pub stage: PaxosStage,
+}
+impl Server {
+    pub fn new(quorums: Quorums, stage: PaxosStage, id: PeerId) -> Self {
+        let now = Instant::now();
+        let mut server = Self {
+            id,
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit: 0,
+            standing: Standing::Follower,
+            election_deadline: now,
+            heartbeat_due: now,
+            quorums: Quorums::majority(quorums.weights),
+            wal: None,
+            stage,
+        };
+        server.reset_election();
+        server
+    }
+
+    /// Rebuilds a server from the log in `dir`, with the term, vote and
+    /// entries it had before going down. What was committed is relearned from
+    /// the leader
+    pub fn recover(
+        dir: &Path,
+        quorums: Quorums,
+        stage: PaxosStage,
+        id: PeerId,
+    ) -> io::Result<Self> {
+        let (wal, records) = Wal::open(dir, &format!("raft{stage}.wal"))?;
+        let mut server = Self::new(quorums, stage, id);
+        for record in records {
+            match record {
+                RaftRecord::Term(term, voted_for) => {
+                    server.term = term;
+                    server.voted_for = voted_for;
+                }
+                RaftRecord::Append(slot, entry) => {
+                    server.log.truncate(slot as usize);
+                    server.log.push(entry);
+                }
+                RaftRecord::Truncate(len) => server.log.truncate(len as usize),
+            }
+        }
+        server.wal = Some(wal);
+        Ok(server)
+    }
+
+    fn record(&mut self, record: RaftRecord) {
+        if let Some(ref mut wal) = self.wal {
+            wal.record(&record);
+        }
+    }
+
+    fn len(&self) -> Slot {
+        self.log.len() as Slot
+    }
+
+    fn term_at(&self, len: Slot) -> Term {
+        match len {
+            0 => 0,
+            len => self.log[len as usize - 1].num.round,
+        }
+    }
+
+    fn reset_election(&mut self) {
+        let wait = rand::thread_rng().gen_range(ELECTION_TIMEOUT..=ELECTION_TIMEOUT * 2);
+        self.election_deadline = Instant::now() + wait;
+    }
+
+    /// Every other server in the stage
+    fn others(&self, peers: &PeerList) -> Vec<PeerId> {
+        peers
+            .acceptors(self.stage)
+            .into_iter()
+            .filter(|peer| *peer != self.id)
+            .collect()
+    }
+
+    /// Anyone hearing about a later term falls back to following in it
+    fn observe(&mut self, term: Term) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.standing = Standing::Follower;
+            self.record(RaftRecord::Term(term, None));
+        }
+    }
+
+    /// Puts `entry` at `slot`, dropping anything already from there up
+    fn append(&mut self, slot: Slot, entry: Proposal) {
+        if slot < self.len() {
+            self.log.truncate(slot as usize);
+            self.record(RaftRecord::Truncate(slot));
+        }
+        self.record(RaftRecord::Append(slot, entry.clone()));
+        self.log.push(entry);
+    }
+
+    fn chosen(&self, slot: Slot) -> Message {
+        Message::Chosen(slot, self.log[slot as usize].clone())
+    }
+
+    /// Everything `to` is missing, starting from where we think it is
+    fn replicate(&self, to: PeerId) -> Message {
+        let Standing::Leader { ref next, .. } = self.standing else {
+            unreachable!("Only leaders replicate");
+        };
+        let prev_len = next.get(&to).copied().unwrap_or(self.len()).min(self.len());
+        let entries = self.log[prev_len as usize..]
+            .iter()
+            .take(MAX_ENTRIES)
+            .cloned()
+            .collect();
+        Message::AppendEntries {
+            term: self.term,
+            prev_len,
+            prev_term: self.term_at(prev_len),
+            entries,
+            commit: self.commit,
+        }
+    }
+
+    fn broadcast(&self, peers: &PeerList) -> Outbox {
+        self.others(peers)
+            .into_iter()
+            .map(|to| (self.replicate(to), vec![to]))
+            .collect()
+    }
+
+    fn lead(&mut self, peers: &PeerList) -> Outbox {
+        let others = self.others(peers);
+        self.standing = Standing::Leader {
+            next: others.iter().map(|id| (*id, self.len())).collect(),
+            matched: others.iter().map(|id| (*id, 0)).collect(),
+        };
+        eprintln!(
+            "{{\"peer_id\": {}, \"action\": \"elected\", \"term\": {}}}",
+            self.id, self.term
+        );
+        self.heartbeat_due = Instant::now() + HEARTBEAT_INTERVAL;
+        let mut out = self.broadcast(peers);
+        out.extend(self.advance(peers));
+        out
+    }
+
+    /// Commits everything up to the last entry of this term a majority holds,
+    /// and tells the clients and learners about it
+    fn advance(&mut self, peers: &PeerList) -> Outbox {
+        let Standing::Leader { ref matched, .. } = self.standing else {
+            return Vec::new();
+        };
+        let committed = self.commit;
+        for len in (self.commit + 1..=self.len()).rev() {
+            // entries from older terms only commit along with one from ours
+            if self.term_at(len) != self.term {
+                break;
+            }
+            let holding = matched
+                .iter()
+                .filter(|(_, m)| **m >= len)
+                .map(|(id, _)| id)
+                .chain(iter::once(&self.id));
+            if self.quorums.weigh(holding) >= self.quorums.phase2 {
+                self.commit = len;
+                break;
+            }
+        }
+
+        // servers ignore it, but a peer can be a client or learner as well
+        let everyone = peers.everyone();
+        (committed..self.commit)
+            .map(|slot| {
+                let msg = self.chosen(slot);
+                msg.paxos_print(self.id, true, &self.log[slot as usize]);
+                (msg, everyone.clone())
+            })
+            .collect()
+    }
+
+    fn on_message(&mut self, from: PeerId, msg: &Message, peers: &PeerList) -> Outbox {
+        match msg {
+            Message::RequestVote {
+                term,
+                log_len,
+                last_term,
+            } => {
+                self.observe(*term);
+                let up_to_date = (*last_term, *log_len) >= (self.term_at(self.len()), self.len());
+                let granted = *term == self.term
+                    && up_to_date
+                    && self.voted_for.is_none_or(|voted| voted == from);
+                if granted {
+                    self.voted_for = Some(from);
+                    self.record(RaftRecord::Term(self.term, Some(from)));
+                    self.reset_election();
+                }
+                vec![(
+                    Message::Vote {
+                        term: self.term,
+                        granted,
+                    },
+                    vec![from],
+                )]
+            }
+
+            Message::Vote { term, granted } => {
+                self.observe(*term);
+                let Standing::Candidate(ref mut votes) = self.standing else {
+                    return Vec::new();
+                };
+                if *term != self.term || !granted {
+                    return Vec::new();
+                }
+                votes.insert(from);
+                if self.quorums.weigh(votes.iter()) >= self.quorums.phase1 {
+                    return self.lead(peers);
+                }
+                Vec::new()
+            }
+
+            Message::AppendEntries {
+                term,
+                prev_len,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                self.observe(*term);
+                let reply = |term, success, log_len| {
+                    vec![(
+                        Message::AppendReply {
+                            term,
+                            success,
+                            log_len,
+                        },
+                        vec![from],
+                    )]
+                };
+                if *term < self.term {
+                    return reply(self.term, false, self.len());
+                }
+                self.standing = Standing::Follower;
+                self.reset_election();
+
+                // our log has to match the leader's up to where these go
+                if *prev_len > self.len() || self.term_at(*prev_len) != *prev_term {
+                    return reply(self.term, false, self.len().min(prev_len.saturating_sub(1)));
+                }
+                for (slot, entry) in (*prev_len..).zip(entries) {
+                    if slot < self.len() && self.term_at(slot + 1) == entry.num.round {
+                        continue;
+                    }
+                    self.append(slot, entry.clone());
+                }
+                let matched = prev_len + entries.len() as Slot;
+                self.commit = self.commit.max((*commit).min(matched));
+                reply(self.term, true, matched)
+            }
+
+            Message::AppendReply {
+                term,
+                success,
+                log_len,
+            } => {
+                self.observe(*term);
+                if *term != self.term {
+                    return Vec::new();
+                }
+                let Standing::Leader {
+                    ref mut next,
+                    ref mut matched,
+                } = self.standing
+                else {
+                    return Vec::new();
+                };
+                next.insert(from, *log_len);
+                if *success {
+                    let held = matched.entry(from).or_default();
+                    *held = (*held).max(*log_len);
+                    self.advance(peers)
+                } else {
+                    vec![(self.replicate(from), vec![from])]
+                }
+            }
+
+            Message::ClientRequest(value) => {
+                if !matches!(self.standing, Standing::Leader { .. }) {
+                    return Vec::new();
+                }
+                // a resent value that is still on its way doesn't need a second entry
+                if self.log[self.commit as usize..]
+                    .iter()
+                    .any(|entry| entry.value == *value)
+                {
+                    return Vec::new();
+                }
+                let entry = Proposal {
+                    num: Ballot {
+                        round: self.term,
+                        proposer: self.id,
+                    },
+                    value: value.clone(),
+                };
+                self.append(self.len(), entry);
+                let mut out = self.broadcast(peers);
+                out.extend(self.advance(peers));
+                out
+            }
+
+            Message::LearnQuery(from_slot) => (*from_slot..self.commit)
+                .map(|slot| (self.chosen(slot), vec![from]))
+                .collect(),
+
+            // Chosen broadcasts and snapshot traffic are for clients and
+            // learners, the log already tells a server what is committed
+            _ => Vec::new(),
+        }
+    }
+
+    fn on_timer(&mut self, peers: &PeerList) -> Outbox {
+        let now = Instant::now();
+        if let Standing::Leader { .. } = self.standing {
+            if now < self.heartbeat_due {
+                return Vec::new();
+            }
+            self.heartbeat_due = now + HEARTBEAT_INTERVAL;
+            return self.broadcast(peers);
+        }
+        if now < self.election_deadline {
+            return Vec::new();
+        }
+
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.record(RaftRecord::Term(self.term, Some(self.id)));
+        self.standing = Standing::Candidate(HashSet::from([self.id]));
+        self.reset_election();
+        if self.quorums.weigh([&self.id]) >= self.quorums.phase1 {
+            return self.lead(peers);
+        }
+        let ask = Message::RequestVote {
+            term: self.term,
+            log_len: self.len(),
+            last_term: self.term_at(self.len()),
+        };
+        vec![(ask, self.others(peers))]
+    }
+}
+
+/// A proposer under Raft. It hands its values to every server, since only the
+/// leader will take them, and keeps resending until it sees them committed
+pub struct Client {
+    outstanding: Vec<Value>,
+    retry_at: Option<Instant>,
+    // Committed entries applied in order, to tell clients what came of theirs
+    applied: Learning,
+    pub stage: PaxosStage,
+}
+impl Client {
+    pub fn new(quorums: Quorums, stage: PaxosStage) -> Self {
+        Self {
+            outstanding: Vec::new(),
+            retry_at: None,
+            applied: Learning::quiet(quorums, stage),
+            stage,
+        }
+    }
+
+    fn on_timer(&mut self) -> Vec<Message> {
+        let now = Instant::now();
+        if self.retry_at.is_none_or(|at| now < at) {
+            return Vec::new();
+        }
+        self.retry_at = Some(now + RETRY_INTERVAL);
+        self.outstanding
+            .iter()
+            .cloned()
+            .map(Message::ClientRequest)
+            .collect()
+    }
+}
+impl Chooser for Client {
+    fn accept_choice(&mut self, slot: Slot, prop: &Proposal) {
+        if let Some(ours) = self.outstanding.iter().position(|v| *v == prop.value) {
+            self.outstanding.remove(ours);
+        }
+        self.applied.accept_choice(slot, prop);
+    }
+}
+
+pub enum RaftRole {
+    Server(Box<Server>),
+    Client(Box<Client>),
+    Learn(Box<Learning>),
+}
+impl RaftRole {
+    /// The role's copy of the committed log, if it keeps one: a learner's, or
+    /// the one a client answers its own clients off
+    fn applier(&mut self) -> Option<&mut Learning> {
+        match self {
+            Self::Learn(learner) => Some(learner),
+            Self::Client(client) => Some(&mut client.applied),
+            Self::Server(_) => None,
+        }
+    }
+}
+impl ConsensusEngine for RaftRole {
+    fn can_propose(&self) -> bool {
+        match self {
+            Self::Client(c) => c.outstanding.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn propose(&mut self, v: Value, peers: &PeerList) -> Outbox {
+        let Self::Client(c) = self else {
+            return Vec::new();
+        };
+        c.outstanding.push(v.clone());
+        c.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+        vec![(Message::ClientRequest(v), peers.acceptors(c.stage))]
+    }
+
+    fn on_message(&mut self, from: PeerId, msg: &Message, peers: &PeerList) -> Outbox {
+        let id = peers.id();
+        let out = match (msg, &mut *self) {
+            (msg, Self::Server(server)) => server.on_message(from, msg, peers),
+
+            (Message::Chosen(slot, prop), Self::Client(client)) => {
+                msg.paxos_print(id, false, prop);
+                client.accept_choice(*slot, prop);
+                Vec::new()
+            }
+
+            (Message::Chosen(slot, prop), Self::Learn(learner)) => {
+                msg.paxos_print(id, false, prop);
+                learner.accept_choice(*slot, prop);
+                Vec::new()
+            }
+
+            (
+                Message::SnapshotChunk {
+                    upto,
+                    offset,
+                    total,
+                    data,
+                },
+                role,
+            ) => role
+                .applier()
+                .and_then(|learner| learner.receive_chunk(from, *upto, *offset, *total, data, id))
+                .into_iter()
+                .map(|msg| (msg, vec![from]))
+                .collect(),
+
+            (Message::SnapshotRequest { upto, offset }, Self::Learn(learner)) => learner
+                .snapshot_chunk(*upto, *offset)
+                .into_iter()
+                .map(|msg| (msg, vec![from]))
+                .collect(),
+
+            (Message::LearnQuery(from_slot), Self::Learn(learner)) => learner
+                .answer(*from_slot)
+                .into_iter()
+                .map(|msg| (msg, vec![from]))
+                .collect(),
+
+            _ => Vec::new(),
+        };
+        if let Some(learner) = self.applier() {
+            learner.deliver(id);
+        }
+        out
+    }
+
+    fn on_timer(&mut self, peers: &PeerList) -> Outbox {
+        match self {
+            Self::Server(server) => server.on_timer(peers),
+            Self::Client(client) => {
+                let mut out: Outbox = client
+                    .on_timer()
+                    .into_iter()
+                    .map(|msg| (msg, peers.acceptors(client.stage)))
+                    .collect();
+                out.extend(client.applied.on_timer(peers));
+                out
+            }
+            Self::Learn(learner) => learner.on_timer(peers).into_iter().collect(),
+        }
+    }
+
+    fn reconfigure(&mut self, quorums: Quorums) {
+        match self {
+            Self::Server(server) => server.quorums = Quorums::majority(quorums.weights),
+            Self::Learn(learner) => learner.reconfigure(quorums),
+            Self::Client(client) => client.applied.reconfigure(quorums),
+        }
+    }
+
+    fn applied(&mut self) -> Vec<Outcome> {
+        self.applier().map_or_else(Vec::new, Learning::outcomes)
+    }
+
+    fn last_request(&self, client: ClientId) -> Option<LastRequest> {
+        match self {
+            Self::Client(c) => c.applied.last_request(client),
+            _ => None,
+        }
+    }
+
+    fn decision(&self) -> Option<&Value> {
+        match self {
+            Self::Learn(learner) => learner.decision(),
+            _ => None,
+        }
+    }
+
+    /// A server's term, vote and log have to be on disk before it answers anyone
+    fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Self::Server(server) => match server.wal {
+                Some(ref mut wal) => wal.sync(),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
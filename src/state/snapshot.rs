@@ -0,0 +1,278 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    kv::{ClientId, Seq, Sessions, Store},
+    paxos::{Slot, Value},
+};
+use crate::messaging::{dist_types::PeerId, Message};
+
+/// How many slots get applied between one snapshot and the next
+pub const SNAPSHOT_EVERY: Slot = 64;
+/// Most bytes of a snapshot sent in one message
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// What applying one decided value did
+pub enum Applied {
+    // Not a key-value command, so nothing changed
+    Skipped,
+    // A command and what it returned
+    Command(Option<String>),
+    // A client request that was already applied in an earlier slot, with
+    // what it returned back then
+    Duplicate(ClientId, Seq, Option<String>),
+}
+
+/// The state built from applying the decided log in slot order: the
+/// key-value map and session table, and the first slot not in them yet.
+/// Once one is taken, every slot below `upto` can be thrown away
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Snapshot {
+    pub upto: Slot,
+    pub store: Store,
+    pub sessions: Sessions,
+    // What slot 0 decided, the stage's decision, kept past compaction so a
+    // peer catching up off a snapshot still learns it
+    pub first: Option<Value>,
+}
+impl Snapshot {
+    /// Applies the value decided in slot `upto` and moves past it. A batch
+    /// gets unpacked and each value in it applied in order
+    pub fn apply<'v>(&mut self, value: &'v Value) -> Vec<(&'v Value, Applied)> {
+        if self.upto == 0 {
+            self.first = Some(value.clone());
+        }
+        let slot = self.upto;
+        self.upto += 1;
+        value
+            .unbatched()
+            .iter()
+            .map(|value| (value, self.apply_one(slot, value)))
+            .collect()
+    }
+
+    fn apply_one(&mut self, slot: Slot, value: &Value) -> Applied {
+        if let Value::Session(client, seq, _) = *value {
+            if let Some(result) = self.sessions.applied(client, seq) {
+                return Applied::Duplicate(client, seq, result);
+            }
+        }
+        let applied = match value.payload() {
+            Value::Kv(command) => Applied::Command(self.store.apply(command)),
+            _ => Applied::Skipped,
+        };
+        if let Value::Session(client, seq, _) = *value {
+            let result = match applied {
+                Applied::Command(ref result) => result.clone(),
+                _ => None,
+            };
+            self.sessions.record(client, seq, slot, result);
+        }
+        applied
+    }
+
+    /// True once enough has been applied since `last` to take another snapshot
+    pub fn is_due(&self, last: &Snapshot) -> bool {
+        self.upto >= last.upto + SNAPSHOT_EVERY
+    }
+
+    /// The piece of the snapshot starting `offset` bytes in
+    pub fn chunk(&self, offset: u64) -> Message {
+        let bytes = bincode::serialize(self).expect("Snapshot is serializable");
+        let start = (offset as usize).min(bytes.len());
+        let end = (start + CHUNK_SIZE).min(bytes.len());
+        Message::SnapshotChunk {
+            upto: self.upto,
+            offset: start as u64,
+            total: bytes.len() as u64,
+            data: bytes[start..end].to_vec(),
+        }
+    }
+
+    /// Answers a request for the rest of a snapshot. If ours is newer than
+    /// the one asked for, it goes out from the start instead
+    pub fn serve(&self, upto: Slot, offset: u64) -> Option<Message> {
+        if self.upto == 0 || self.upto < upto {
+            return None;
+        }
+        Some(self.chunk(if self.upto == upto { offset } else { 0 }))
+    }
+
+    /// Reads the snapshot `name` in `dir`, if one was ever saved there
+    pub fn load(dir: &Path, name: &str) -> io::Result<Option<Self>> {
+        match fs::read(dir.join(name)) {
+            Ok(bytes) => bincode::deserialize(&bytes)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Saves the snapshot as `name` in `dir`. It is written and fsync'd to
+    /// the side first, so a crash leaves either the old snapshot or this one
+    pub fn save(&self, dir: &Path, name: &str) -> io::Result<()> {
+        let temp = dir.join(format!("{name}.tmp"));
+        let mut file = File::create(&temp)?;
+        file.write_all(&bincode::serialize(self).expect("Snapshot is serializable"))?;
+        file.sync_all()?;
+        fs::rename(&temp, dir.join(name))?;
+        File::open(dir)?.sync_all()
+    }
+}
+
+/// What taking in one chunk of a snapshot did
+pub enum Received {
+    // Not the piece we were waiting on
+    Ignored,
+    // The next piece, with more still to come
+    Progress,
+    // The last piece, and the snapshot they make up
+    Done(Snapshot),
+}
+
+/// A snapshot on its way in, one chunk at a time. Every chunk has to come
+/// from the peer that sent the first one, since two peers' snapshots up to
+/// the same slot needn't be the same bytes
+pub struct Transfer {
+    pub upto: Slot,
+    pub from: PeerId,
+    total: u64,
+    bytes: Vec<u8>,
+    // When the last chunk we took came in
+    heard: Instant,
+}
+impl Transfer {
+    pub fn new(upto: Slot, from: PeerId, total: u64) -> Self {
+        Self {
+            upto,
+            from,
+            total,
+            bytes: Vec::new(),
+            heard: Instant::now(),
+        }
+    }
+
+    /// How much has come in, which is where the next chunk starts
+    pub fn offset(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    /// How long it has been since the last chunk we took
+    pub fn quiet_for(&self) -> Duration {
+        self.heard.elapsed()
+    }
+
+    /// Adds a chunk if it is from our source and picks up right where the
+    /// last one stopped
+    pub fn receive(&mut self, from: PeerId, offset: u64, data: &[u8]) -> io::Result<Received> {
+        if from != self.from || offset != self.offset() || data.is_empty() {
+            return Ok(Received::Ignored);
+        }
+        self.heard = Instant::now();
+        self.bytes.extend_from_slice(data);
+        if self.offset() < self.total {
+            return Ok(Received::Progress);
+        }
+        bincode::deserialize(&self.bytes)
+            .map(Received::Done)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::kv::Command;
+
+    /// A snapshot a few chunks long
+    fn snapshot() -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for key in 0..40 {
+            let command = Command::parse(&format!("put k{key} {}", "x".repeat(1000)));
+            snapshot.apply(&Value::Kv(command.expect("Valid command")));
+        }
+        snapshot
+    }
+
+    /// The chunk starting `offset` bytes in, and how long the whole thing is
+    fn piece(snapshot: &Snapshot, offset: u64) -> (u64, Vec<u8>, u64) {
+        match snapshot.chunk(offset) {
+            Message::SnapshotChunk {
+                offset,
+                data,
+                total,
+                ..
+            } => (offset, data, total),
+            _ => unreachable!("chunk only makes snapshot chunks"),
+        }
+    }
+
+    #[test]
+    fn receive_takes_chunks_in_order_from_one_peer() -> io::Result<()> {
+        let sent = snapshot();
+        let (_, first, total) = piece(&sent, 0);
+        assert!(total > 2 * CHUNK_SIZE as u64);
+        let mut transfer = Transfer::new(sent.upto, 3, total);
+
+        // ahead of what we have, so it would leave a hole
+        let (offset, data, _) = piece(&sent, CHUNK_SIZE as u64);
+        assert!(matches!(
+            transfer.receive(3, offset, &data)?,
+            Received::Ignored
+        ));
+        assert_eq!(transfer.offset(), 0);
+
+        assert!(matches!(
+            transfer.receive(3, 0, &first)?,
+            Received::Progress
+        ));
+        // the same chunk again, or the next one from someone else
+        assert!(matches!(transfer.receive(3, 0, &first)?, Received::Ignored));
+        assert!(matches!(
+            transfer.receive(4, offset, &data)?,
+            Received::Ignored
+        ));
+        assert_eq!(transfer.offset(), first.len() as u64);
+
+        // picking up from where we stopped, as after asking again
+        let received = loop {
+            let (offset, data, _) = piece(&sent, transfer.offset());
+            match transfer.receive(3, offset, &data)? {
+                Received::Progress => continue,
+                received => break received,
+            }
+        };
+        let Received::Done(got) = received else {
+            panic!("the last chunk finishes the snapshot");
+        };
+        assert_eq!(got.upto, sent.upto);
+        assert_eq!(got.store.json(), sent.store.json());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_answers_a_retry_with_the_first_result() {
+        let request =
+            |seq, line| Value::Session(7, seq, Box::new(Value::from_line(line).expect("Parses")));
+        let mut state = Snapshot::default();
+        state.apply(&request(1, "kv:put a 1"));
+        state.apply(&request(2, "kv:put a 2"));
+        assert_eq!(state.sessions.last(7), Some((2, 1, Some("1".to_string()))));
+
+        let retried = request(2, "kv:put a 2");
+        let applied = state.apply(&retried);
+        assert!(matches!(
+            applied[..],
+            [(_, Applied::Duplicate(7, 2, Some(ref result)))] if result == "1"
+        ));
+        assert_eq!(state.store.get("a"), Some("2".to_string()));
+        assert_eq!(state.sessions.last(7), Some((2, 1, Some("1".to_string()))));
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use super::paxos::{PaxosStage, Slot, Value};
+use crate::{messaging::dist_types::PeerId, setup::hostsfile::PeerList};
+
+/// Runs the hostsfile's stages one after another. A stage's decision is
+/// whatever gets chosen in its first slot, and the next stage only starts once
+/// there is one. Every peer sees the same decision before moving on, so they
+/// all agree on any reconfig it makes. A deadline doesn't skip a stage, its
+/// proposers settle it with a no-op instead
+pub struct Schedule {
+    order: Vec<PaxosStage>,
+    // How many stages have started, so `order[..started]`
+    started: usize,
+    started_at: Instant,
+    deadlines: HashMap<PaxosStage, Duration>,
+    // Stages whose proposers start from the previous stage's decision
+    seeded: HashSet<PaxosStage>,
+    decisions: HashMap<PaxosStage, Value>,
+    reported: bool,
+}
+impl Schedule {
+    pub fn new(peers: &PeerList) -> Self {
+        let order = peers.stages();
+        Self {
+            deadlines: order
+                .iter()
+                .filter_map(|stage| Some((*stage, peers.deadline(*stage)?)))
+                .collect(),
+            seeded: order
+                .iter()
+                .copied()
+                .filter(|stage| peers.seeded(*stage))
+                .collect(),
+            started: order.len().min(1),
+            order,
+            started_at: Instant::now(),
+            decisions: HashMap::new(),
+            reported: false,
+        }
+    }
+
+    /// True once `stage` is running. Only then can its proposers take values
+    pub fn has_started(&self, stage: PaxosStage) -> bool {
+        self.order[..self.started].contains(&stage)
+    }
+
+    /// The latest stage to start
+    pub fn current(&self) -> Option<PaxosStage> {
+        self.order.get(self.started.checked_sub(1)?).copied()
+    }
+
+    /// True once `stage` has its decision
+    pub fn is_decided(&self, stage: PaxosStage) -> bool {
+        self.decisions.contains_key(&stage)
+    }
+
+    /// What `stage` decided, if we have seen it
+    pub fn decision(&self, stage: PaxosStage) -> Option<&Value> {
+        self.decisions.get(&stage)
+    }
+
+    /// Takes note of a value chosen in a stage. True if it was the stage's
+    /// decision and we hadn't seen it yet
+    pub fn observe(&mut self, stage: PaxosStage, slot: Slot, value: &Value) -> bool {
+        if slot != 0 || self.decisions.contains_key(&stage) {
+            return false;
+        }
+        self.decisions.insert(stage, value.clone());
+        true
+    }
+
+    /// The stage that runs after `stage`
+    pub fn after(&self, stage: PaxosStage) -> Option<PaxosStage> {
+        let index = self.order.iter().position(|s| *s == stage)?;
+        self.order.get(index + 1).copied()
+    }
+
+    /// The latest stage to start, if its deadline ran out before it decided
+    /// anything
+    pub fn overdue(&self) -> Option<PaxosStage> {
+        let latest = self.current()?;
+        let timed_out = self
+            .deadlines
+            .get(&latest)
+            .is_some_and(|deadline| self.started_at.elapsed() >= *deadline);
+        (timed_out && !self.decisions.contains_key(&latest)).then_some(latest)
+    }
+
+    /// The latest stage to start, if it has its decision
+    fn latest_done(&self) -> Option<PaxosStage> {
+        let latest = self.current()?;
+        self.decisions.contains_key(&latest).then_some(latest)
+    }
+
+    /// Starts the next stage once the latest one is done. Hands back the stage
+    /// that started, with the decision it should propose first if it is
+    /// seeded from the one before
+    pub fn advance(&mut self, id: PeerId) -> Option<(PaxosStage, Option<Value>)> {
+        let next = *self.order.get(self.started)?;
+        let current = self.latest_done()?;
+
+        self.started += 1;
+        self.started_at = Instant::now();
+        eprintln!("{{\"peer_id\": {id}, \"action\": \"stage_started\", \"stage\": {next}}}");
+        let seed = self
+            .seeded
+            .contains(&next)
+            .then(|| self.decisions.get(&current).cloned())
+            .flatten()
+            .filter(|seed| *seed != Value::Noop);
+        Some((next, seed))
+    }
+
+    /// Prints every stage's decision once the last stage is done. Stages that
+    /// ran out of time and got settled with a no-op show up as null
+    pub fn report(&mut self, id: PeerId) {
+        if self.reported || self.started < self.order.len() || self.latest_done().is_none() {
+            return;
+        }
+
+        self.reported = true;
+        let stages: Vec<String> = self
+            .order
+            .iter()
+            .map(|stage| {
+                let value = self
+                    .decisions
+                    .get(stage)
+                    .filter(|value| **value != Value::Noop)
+                    .map_or_else(|| "null".to_string(), Value::json);
+                format!("{{\"stage\": {stage}, \"value\": {value}}}")
+            })
+            .collect();
+        eprintln!(
+            "{{\"peer_id\": {id}, \"action\": \"report\", \"stages\": [{}]}}",
+            stages.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup::hostsfile::peers;
+
+    const TWO_STAGES: &str = "peer1:proposer1,proposer2\npeer2:acceptor1,acceptor2\n";
+
+    #[test]
+    fn advance_waits_for_the_first_slot_even_past_the_deadline() {
+        let mut schedule = Schedule::new(&peers(&format!("stage1:deadline=0\n{TWO_STAGES}")));
+        assert_eq!(schedule.overdue(), Some(1));
+        assert_eq!(schedule.advance(1), None);
+
+        assert!(!schedule.observe(1, 1, &Value::Text("late".into())));
+        assert_eq!(schedule.advance(1), None);
+
+        assert!(schedule.observe(1, 0, &Value::Noop));
+        assert_eq!(schedule.advance(1), Some((2, None)));
+        assert!(schedule.has_started(2));
+        assert_eq!(schedule.overdue(), None);
+    }
+
+    #[test]
+    fn advance_seeds_from_the_decision_unless_it_was_a_noop() {
+        let seeded = format!("stage2:seed\n{TWO_STAGES}");
+        let mut schedule = Schedule::new(&peers(&seeded));
+        schedule.observe(1, 0, &Value::Text("a".into()));
+        assert_eq!(
+            schedule.advance(1),
+            Some((2, Some(Value::Text("a".into()))))
+        );
+
+        let mut schedule = Schedule::new(&peers(&seeded));
+        schedule.observe(1, 0, &Value::Noop);
+        assert_eq!(schedule.advance(1), Some((2, None)));
+    }
+}
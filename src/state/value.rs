@@ -0,0 +1,278 @@
+use std::{fmt::Write, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    kv::{ClientId, Command, Seq},
+    paxos::Weight,
+};
+
+/// Payload that gets decided. Text stays text so it prints as-is, anything
+/// read off a file is kept as raw bytes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Bytes(Vec<u8>),
+    // The acceptors (by hostname) and their weights for the next stage
+    Reconfig(Vec<(String, Weight)>),
+    // An operation on the replicated key-value store
+    Kv(Command),
+    // Another value sent as request `Seq` of an outside client, so a retry
+    // that gets decided again is only applied once
+    Session(ClientId, Seq, Box<Value>),
+    // Several values proposed together in one slot, applied in this order
+    Batch(Vec<Value>),
+    // Fills a slot a new leader found nothing accepted in, below others that
+    // had values, so the log has no hole to wait on
+    Noop,
+}
+impl Value {
+    /// Parses a value as given on the command line, where `@path` means the
+    /// contents of that file and anything else is read as in `from_line`
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        match arg.strip_prefix('@') {
+            Some(path) => fs::read(Path::new(path))
+                .map(Value::Bytes)
+                .map_err(|e| format!("Can't read {path}: {e}")),
+            None => Self::from_line(arg),
+        }
+    }
+
+    /// Parses a value sent as text, where `reconfig:peer2,peer3*2` is a new
+    /// acceptor set, `kv:put x 1` (or get, delete, cas) a key-value command,
+    /// `session:7:3:kv:put x 1` that command as request 3 of client 7, and
+    /// anything else plain text
+    pub fn from_line(line: &str) -> Result<Self, String> {
+        if let Some(session) = line.strip_prefix("session:") {
+            let mut parts = session.splitn(3, ':');
+            let (Some(client), Some(seq), Some(rest)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err("Sessions are written session:CLIENT:SEQ:VALUE".into());
+            };
+            let client = client.parse().map_err(|e| format!("Bad client id: {e}"))?;
+            let seq = seq
+                .parse()
+                .map_err(|e| format!("Bad sequence number: {e}"))?;
+            return match Self::from_line(rest)? {
+                Value::Session(..) => Err("Sessions can't be nested".into()),
+                inner => Ok(Value::Session(client, seq, Box::new(inner))),
+            };
+        }
+        if let Some(command) = line.strip_prefix("kv:") {
+            return Command::parse(command).map(Value::Kv);
+        }
+        let Some(members) = line.strip_prefix("reconfig:") else {
+            return Ok(Value::Text(line.into()));
+        };
+        members
+            .split(',')
+            .map(|member| match member.split_once('*') {
+                Some((name, weight)) => weight
+                    .parse()
+                    .map(|weight| (name.to_string(), weight))
+                    .map_err(|e| format!("Bad weight for {name}: {e}")),
+                None => Ok((member.to_string(), 1)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Value::Reconfig)
+    }
+
+    /// Size of the payload in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Text(text) => text.len(),
+            Self::Bytes(bytes) => bytes.len(),
+            Self::Reconfig(members) => members.iter().map(|(name, _)| name.len() + 8).sum(),
+            Self::Kv(command) => command.size(),
+            Self::Session(_, _, inner) => inner.size() + 16,
+            Self::Batch(values) => values.iter().map(Value::size).sum(),
+            Self::Noop => 0,
+        }
+    }
+
+    /// The value itself, outside of any client session
+    pub fn payload(&self) -> &Value {
+        match self {
+            Self::Session(_, _, inner) => inner,
+            v => v,
+        }
+    }
+
+    /// The values this one stands for in the log: what a batch holds, or
+    /// just the value itself
+    pub fn unbatched(&self) -> &[Value] {
+        match self {
+            Self::Batch(values) => values,
+            v => std::slice::from_ref(v),
+        }
+    }
+
+    /// The acceptors a reconfig among the values this one stands for asks
+    /// for, if it holds one
+    pub fn reconfig(&self) -> Option<&[(String, Weight)]> {
+        self.unbatched().iter().find_map(|v| match v.payload() {
+            Self::Reconfig(members) => Some(members.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// Splits the reconfigs off the values this one stands for. Hands back
+    /// the rest as one value, if anything is left, and the reconfigs
+    pub fn split_reconfigs(&self) -> (Option<Value>, Vec<&Value>) {
+        let (reconfigs, mut kept): (Vec<&Value>, Vec<&Value>) = self
+            .unbatched()
+            .iter()
+            .partition(|v| matches!(v.payload(), Self::Reconfig(_)));
+        let rest = match kept.len() {
+            0 => None,
+            1 => kept.pop().cloned(),
+            _ => Some(Self::Batch(kept.into_iter().cloned().collect())),
+        };
+        (rest, reconfigs)
+    }
+
+    /// Renders the value as a quoted JSON string. Bytes come out as hex so the
+    /// output stays valid no matter what is in them
+    pub fn json(&self) -> String {
+        let mut out = String::from("\"");
+        self.write_json(&mut out);
+        out.push('"');
+        out
+    }
+
+    /// Writes the inside of the quoted JSON string
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Self::Text(text) => escape(out, text),
+            Self::Kv(command) => {
+                out.push_str("kv:");
+                escape(out, &command.text());
+            }
+            Self::Session(client, seq, inner) => {
+                let _ = write!(out, "session:{client}:{seq}:");
+                inner.write_json(out);
+            }
+            Self::Batch(values) => {
+                out.push_str("batch[");
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    value.write_json(out);
+                }
+                out.push(']');
+            }
+            Self::Bytes(bytes) => {
+                out.push_str("0x");
+                for b in bytes {
+                    let _ = write!(out, "{b:02x}");
+                }
+            }
+            Self::Noop => out.push_str("noop"),
+            Self::Reconfig(members) => {
+                out.push_str("reconfig:");
+                for (i, (name, weight)) in members.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    let _ = write!(out, "{sep}{name}*{weight}");
+                }
+            }
+        }
+    }
+}
+
+/// Writes text out with everything JSON wants escaped
+fn escape(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_line_only_reads_commands_behind_a_prefix() {
+        let text = |s: &str| Ok(Value::Text(s.into()));
+        assert_eq!(
+            Value::from_line("put on your shoes"),
+            text("put on your shoes")
+        );
+        assert_eq!(Value::from_line("get ready"), text("get ready"));
+        assert_eq!(
+            Value::from_line("kv:put a 1"),
+            Ok(Value::Kv(Command::Put("a".into(), "1".into())))
+        );
+        assert_eq!(
+            Value::from_line("kv:get a"),
+            Ok(Value::Kv(Command::Get("a".into())))
+        );
+        assert!(Value::from_line("kv:put a").is_err());
+        assert!(Value::from_line("kv:jump a").is_err());
+        assert!(Value::from_line("kv:").is_err());
+    }
+
+    #[test]
+    fn from_line_reads_sessions_and_reconfigs() {
+        assert_eq!(
+            Value::from_line("session:7:3:kv:delete x"),
+            Ok(Value::Session(
+                7,
+                3,
+                Box::new(Value::Kv(Command::Delete("x".into())))
+            ))
+        );
+        assert_eq!(
+            Value::from_line("session:7:3:hello there"),
+            Ok(Value::Session(
+                7,
+                3,
+                Box::new(Value::Text("hello there".into()))
+            ))
+        );
+        assert!(Value::from_line("session:7:3:session:7:4:x").is_err());
+        assert_eq!(
+            Value::from_line("reconfig:peer2,peer3*2"),
+            Ok(Value::Reconfig(vec![
+                ("peer2".into(), 1),
+                ("peer3".into(), 2)
+            ]))
+        );
+    }
+
+    #[test]
+    fn json_puts_the_prefix_back() {
+        let value = Value::from_line("kv:put a \"1\"").expect("Valid command");
+        assert_eq!(value.json(), r#""kv:put a \"1\"""#);
+    }
+
+    #[test]
+    fn split_reconfigs_keeps_everything_else() {
+        let reconfig = Value::from_line("reconfig:peer2,peer3").expect("Parses");
+        let text = Value::Text("a".into());
+        assert_eq!(reconfig.split_reconfigs(), (None, vec![&reconfig]));
+        assert_eq!(text.split_reconfigs(), (Some(text.clone()), vec![]));
+
+        let session = Value::Session(1, 1, Box::new(reconfig.clone()));
+        let batch = Value::Batch(vec![text.clone(), session.clone(), Value::Noop]);
+        assert_eq!(
+            batch.split_reconfigs(),
+            (
+                Some(Value::Batch(vec![text.clone(), Value::Noop])),
+                vec![&session]
+            )
+        );
+        let batch = Value::Batch(vec![reconfig.clone(), text.clone()]);
+        assert_eq!(batch.split_reconfigs(), (Some(text), vec![&reconfig]));
+    }
+}
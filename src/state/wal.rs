@@ -0,0 +1,163 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::paxos::{Ballot, Proposal, Slot};
+
+/// A single change to an acceptor's state, in the order it happened
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WalRecord {
+    Promise(Ballot),
+    Accept(Slot, Proposal),
+}
+
+/// Append only log of state changes, acceptor ones unless told otherwise.
+/// Records are buffered as they happen and only hit the disk (and get fsync'd)
+/// together in `sync`
+pub struct Wal<R = WalRecord> {
+    file: File,
+    path: PathBuf,
+    unsynced: Vec<u8>,
+    records: PhantomData<R>,
+}
+impl<R: Serialize + DeserializeOwned> Wal<R> {
+    /// Opens (or creates) the log `name` in `dir`, handing back every record
+    /// that made it to disk before we last went down
+    pub fn open(dir: &Path, name: &str) -> io::Result<(Self, Vec<R>)> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(name);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = Vec::new();
+        let mut rest = &bytes[..];
+        while let Some((len, body)) = rest.split_first_chunk::<4>() {
+            let len = u32::from_be_bytes(*len) as usize;
+            let Some(Ok(record)) = body.get(..len).map(bincode::deserialize) else {
+                break;
+            };
+            records.push(record);
+            rest = &body[len..];
+        }
+        // A torn record at the end was never synced, so its ack never went out.
+        // Cut it off so new records don't land behind garbage
+        file.set_len((bytes.len() - rest.len()) as u64)?;
+
+        Ok((
+            Self {
+                file,
+                path,
+                unsynced: Vec::new(),
+                records: PhantomData,
+            },
+            records,
+        ))
+    }
+
+    pub fn record(&mut self, record: &R) {
+        frame(&mut self.unsynced, record);
+    }
+
+    /// Replaces the whole log with `records`, which have to say everything
+    /// the old one did that is still needed. The new log is written and
+    /// fsync'd to the side first, so a crash leaves one log or the other
+    pub fn rewrite(&mut self, records: &[R]) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        for record in records {
+            frame(&mut bytes, record);
+        }
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.unsynced.clear();
+        Ok(())
+    }
+
+    /// Writes out and fsyncs everything recorded since the last sync
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&self.unsynced)?;
+        self.file.sync_data()?;
+        self.unsynced.clear();
+        Ok(())
+    }
+}
+
+/// Appends a record prefixed with its length
+fn frame<R: Serialize>(out: &mut Vec<u8>, record: &R) {
+    let body = bincode::serialize(record).expect("Record is serializable");
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(&body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn promise(round: u64) -> WalRecord {
+        WalRecord::Promise(Ballot { round, proposer: 1 })
+    }
+
+    fn rounds(records: &[WalRecord]) -> Vec<u64> {
+        records
+            .iter()
+            .map(|record| match record {
+                WalRecord::Promise(num) => num.round,
+                WalRecord::Accept(..) => panic!("only promises were written"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn open_cuts_off_a_torn_record() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("paxos-wal-torn-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let (mut wal, records) = Wal::<WalRecord>::open(&dir, "acceptor1.wal")?;
+        assert!(records.is_empty());
+        wal.record(&promise(1));
+        wal.record(&promise(2));
+        wal.sync()?;
+        let whole = fs::metadata(dir.join("acceptor1.wal"))?.len();
+
+        // half of a third record, as if we went down partway through writing it
+        let mut torn = Vec::new();
+        frame(&mut torn, &promise(3));
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join("acceptor1.wal"))?;
+        file.write_all(&torn[..torn.len() / 2])?;
+
+        let (mut wal, records) = Wal::<WalRecord>::open(&dir, "acceptor1.wal")?;
+        assert_eq!(rounds(&records), [1, 2]);
+        assert_eq!(fs::metadata(dir.join("acceptor1.wal"))?.len(), whole);
+
+        // anything written after the cut reads back right behind the good records
+        wal.record(&promise(4));
+        wal.sync()?;
+        let (_, records) = Wal::<WalRecord>::open(&dir, "acceptor1.wal")?;
+        assert_eq!(rounds(&records), [1, 2, 4]);
+
+        fs::remove_dir_all(&dir)
+    }
+}